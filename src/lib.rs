@@ -18,36 +18,350 @@
 //! Provides bindings (through feature flags) for conversion to/from
 //! various libraries
 
+#[cfg(feature = "flate2")]
+extern crate flate2;
+#[cfg(feature = "regex")]
+extern crate regex;
+
 // traits
 use std::io::{Read, Write};
 
+use std::error;
+use std::fmt;
 use std::io;
+use std::io::BufWriter;
 use std::fs;
-use std::ffi::OsString;
-use std::collections::{HashMap};
+use std::process;
+use std::str;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::ffi::{OsStr, OsString};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::collections::hash_map;
-use std::path::{Path};
+use std::vec;
+use std::ops::{Deref, DerefMut};
+use std::path::{Component, Path, PathBuf};
 
 /// representation of a directory
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Dir {
     items: HashMap<OsString, Entry>,
 }
 
 /// representation of a file
+///
+/// The contents are reference counted so that trees can share them (see
+/// `Dir::clone_shared`). Shared contents are copied the first time they
+/// are modified.
 #[derive(Debug, PartialEq)]
 pub struct File {
-    bytes: Vec<u8>,
+    bytes: Arc<Vec<u8>>,
+}
+
+/// what a resumed dump did, see `Dir::dump_resume`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DumpStats {
+    /// files which were (re)written
+    pub written: usize,
+    /// files which were already on disk and left alone
+    pub skipped: usize,
+}
+
+/// a path changed differently on both sides of `Dir::merge3`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conflict {
+    pub path: PathBuf,
+}
+
+/// a difference between two trees, see `Dir::diff`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Diff {
+    /// the entry only exists in the other tree
+    Added(PathBuf),
+    /// the entry only exists in this tree
+    Removed(PathBuf),
+    /// the file exists in both trees with different contents
+    ModifiedFile(PathBuf),
+}
+
+/// aggregate numbers about a tree, see `Dir::stats`
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TreeStats {
+    pub files: usize,
+    /// directories below the root
+    pub dirs: usize,
+    pub bytes: u64,
+    /// the most components in the path of any entry
+    pub max_depth: usize,
+    /// the path and size of the largest file (the first by path on ties)
+    pub largest_file: Option<(PathBuf, u64)>,
+    /// file counts by size: index 0 counts empty files and index `i`
+    /// counts files of `2^(i-1)` up to `2^i - 1` bytes
+    pub size_histogram: Vec<usize>,
+}
+
+/// what to include in `Dir::tree_report`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TreeReportOpts {
+    /// show the size of each file
+    pub file_sizes: bool,
+    /// show the total size of each directory
+    pub dir_sizes: bool,
+    /// end with a line counting directories, files and bytes
+    pub summary: bool,
+}
+
+/// a file format recognized by `File::detect_format`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FileFormat {
+    Png,
+    Jpeg,
+    Gif,
+    Webp,
+    Pdf,
+    Zip,
+    Gzip,
+    Bzip2,
+    Xz,
+    Zstd,
+    SevenZip,
+    Tar,
+    Elf,
+    MachO,
+    Wasm,
+}
+
+/// magic bytes expected at an offset
+type Magic = (usize, &'static [u8]);
+
+/// the signatures of each format: all of the magic bytes must match
+const FORMAT_SIGNATURES: &[(FileFormat, &[Magic])] = &[
+    (FileFormat::Png, &[(0, b"\x89PNG\r\n\x1a\n")]),
+    (FileFormat::Jpeg, &[(0, b"\xff\xd8\xff")]),
+    (FileFormat::Gif, &[(0, b"GIF87a")]),
+    (FileFormat::Gif, &[(0, b"GIF89a")]),
+    (FileFormat::Webp, &[(0, b"RIFF"), (8, b"WEBP")]),
+    (FileFormat::Pdf, &[(0, b"%PDF-")]),
+    (FileFormat::Zip, &[(0, b"PK\x03\x04")]),
+    (FileFormat::Zip, &[(0, b"PK\x05\x06")]),
+    (FileFormat::Gzip, &[(0, b"\x1f\x8b")]),
+    (FileFormat::Bzip2, &[(0, b"BZh")]),
+    (FileFormat::Xz, &[(0, b"\xfd7zXZ\x00")]),
+    (FileFormat::Zstd, &[(0, b"\x28\xb5\x2f\xfd")]),
+    (FileFormat::SevenZip, &[(0, b"7z\xbc\xaf\x27\x1c")]),
+    (FileFormat::Tar, &[(257, b"ustar")]),
+    (FileFormat::Elf, &[(0, b"\x7fELF")]),
+    (FileFormat::MachO, &[(0, b"\xfe\xed\xfa\xce")]),
+    (FileFormat::MachO, &[(0, b"\xfe\xed\xfa\xcf")]),
+    (FileFormat::MachO, &[(0, b"\xce\xfa\xed\xfe")]),
+    (FileFormat::MachO, &[(0, b"\xcf\xfa\xed\xfe")]),
+    (FileFormat::Wasm, &[(0, b"\x00asm")]),
+];
+
+/// a problem with an entry found by `Dir::fsck`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Issue {
+    pub path: PathBuf,
+    pub reason: IssueReason,
+}
+
+/// why an entry was reported by `Dir::fsck`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IssueReason {
+    /// the name is empty
+    EmptyName,
+    /// the name is `.` or `..`
+    DotName,
+    /// the name contains a path separator (`/`, or `\` on windows)
+    Separator,
+    /// the name contains a NUL byte
+    Nul,
+    /// another entry in the same directory has the same name when ASCII
+    /// case is ignored
+    CaseCollision,
+}
+
+/// limits checked by `Dir::dump_checked` before anything is written
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DumpLimits {
+    /// the longest allowed path (in bytes) of any entry, including the
+    /// path being dumped to
+    pub max_path_len: Option<usize>,
+    /// the most components allowed in an entry's path below the root
+    pub max_depth: Option<usize>,
+    /// also reject names windows can't create, e.g. `CON`, `a:b` or
+    /// `a\b`, and names which collide when case is ignored
+    pub windows_names: bool,
+}
+
+/// why `Dir::dump_checked` failed
+#[derive(Debug)]
+pub enum DumpLimitError {
+    /// the full path of this entry is longer than `max_path_len`
+    PathTooLong(PathBuf),
+    /// this entry is nested deeper than `max_depth`
+    TooDeep(PathBuf),
+    /// this entry's name can't be created (see `Dir::fsck`)
+    IllegalName(PathBuf),
+    /// the checks passed but dumping failed
+    Io(io::Error),
+}
+
+impl fmt::Display for DumpLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            DumpLimitError::PathTooLong(ref p) => write!(f, "path too long: {}", p.display()),
+            DumpLimitError::TooDeep(ref p) => write!(f, "path too deep: {}", p.display()),
+            DumpLimitError::IllegalName(ref p) => write!(f, "illegal name: {}", p.display()),
+            DumpLimitError::Io(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl error::Error for DumpLimitError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            DumpLimitError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DumpLimitError {
+    fn from(e: io::Error) -> DumpLimitError {
+        DumpLimitError::Io(e)
+    }
+}
+
+/// whether windows refuses to create a file or directory with this name
+fn is_illegal_on_windows(name: &str) -> bool {
+    const RESERVED: &[&str] = &[
+        "CON", "PRN", "AUX", "NUL",
+        "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+        "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+    ];
+    let stem = name.split('.').next().unwrap_or("").trim_end();
+    RESERVED.iter().any(|r| r.eq_ignore_ascii_case(stem))
+        || name.ends_with('.')
+        || name.ends_with(' ')
+        || name.chars().any(|c| c < ' ' || "<>:\"\\|?*".contains(c))
+}
+
+/// how `Dir::merge` handles an incoming entry whose name is taken
+///
+/// Two directories never collide: they are merged recursively.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergePolicy {
+    /// replace the existing entry with the incoming one
+    Overwrite,
+    /// keep the existing entry and drop the incoming one
+    Skip,
+    /// fail the merge with an `AlreadyExists` error
+    Error,
+}
+
+/// what `Dir::merge` would do, see `Dir::merge_preview`
+///
+/// Each list is sorted by path. An incoming directory which doesn't exist
+/// yet is listed once in `added`, not once per entry inside it.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MergePlan {
+    pub added: Vec<PathBuf>,
+    pub overwritten: Vec<PathBuf>,
+    pub skipped: Vec<PathBuf>,
+    /// collisions which make the merge fail (with `MergePolicy::Error`)
+    pub conflicts: Vec<PathBuf>,
+}
+
+/// what merging one incoming entry does
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MergeAction {
+    Add,
+    Overwrite,
+    Skip,
+    Recurse,
+    Conflict,
+}
+
+impl MergePolicy {
+    fn action(self, existing: Option<&Entry>, incoming: &Entry) -> MergeAction {
+        match (existing, incoming) {
+            (None, _) => MergeAction::Add,
+            (Some(&Entry::Dir(_)), &Entry::Dir(_)) => MergeAction::Recurse,
+            (Some(_), _) => match self {
+                MergePolicy::Overwrite => MergeAction::Overwrite,
+                MergePolicy::Skip => MergeAction::Skip,
+                MergePolicy::Error => MergeAction::Conflict,
+            },
+        }
+    }
+}
+
+/// file contents as returned by `File::maybe_compress`
+#[cfg(feature = "flate2")]
+#[derive(Debug, Clone, PartialEq)]
+pub enum StoredBytes {
+    /// the original contents, shared with the file
+    Raw(Arc<Vec<u8>>),
+    /// the contents compressed with gzip
+    Compressed(Vec<u8>),
+}
+
+#[cfg(feature = "flate2")]
+impl StoredBytes {
+    /// get back the file the bytes were stored from
+    pub fn to_file(&self) -> io::Result<File> {
+        match *self {
+            StoredBytes::Raw(ref bytes) => Ok(File { bytes: bytes.clone() }),
+            StoredBytes::Compressed(ref bytes) => {
+                let mut out = Vec::new();
+                flate2::read::GzDecoder::new(&bytes[..]).read_to_end(&mut out)?;
+                Ok(File::new(out))
+            }
+        }
+    }
 }
 
+/// files with more entropy (in bits per byte) than this are assumed to
+/// be compressed already, see `File::maybe_compress`
+#[cfg(feature = "flate2")]
+const COMPRESSED_ENTROPY: f64 = 7.5;
+
 /// possible entries in a directory
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Entry {
     File(File),
     Dir(Dir),
 }
 
 impl Entry {
+    /// create a file entry from anything that converts into bytes
+    pub fn file<B: Into<Vec<u8>>>(bytes: B) -> Entry {
+        Entry::File(File::new(bytes.into()))
+    }
+
+    /// create an entry for a new, empty directory
+    pub fn empty_dir() -> Entry {
+        Entry::Dir(Dir::new())
+    }
+
+    /// a copy of the entry sharing file contents with this one, see
+    /// `Dir::clone_shared`
+    pub fn clone_shared(&self) -> Entry {
+        match *self {
+            Entry::File(ref f) => Entry::File(f.clone_shared()),
+            Entry::Dir(ref d) => Entry::Dir(d.clone_shared()),
+        }
+    }
+
+    /// the number of bytes of the file, or of all files in the dir
+    fn size(&self) -> u64 {
+        match *self {
+            Entry::File(ref f) => f.bytes.len() as u64,
+            Entry::Dir(ref d) => d.total_size(),
+        }
+    }
+
     pub fn dump(&self, path: &Path) -> io::Result<()> {
         match *self {
             Entry::File(ref f) => f.dump(path),
@@ -60,7 +374,7 @@ impl Entry {
 impl File {
     pub fn new(bytes: Vec<u8>) -> File {
         File {
-            bytes: bytes,
+            bytes: Arc::new(bytes),
         }
     }
 
@@ -76,11 +390,328 @@ impl File {
         &self.bytes
     }
 
+    /// mutable access to the contents, copying them first if shared
+    fn bytes_mut(&mut self) -> &mut Vec<u8> {
+        Arc::make_mut(&mut self.bytes)
+    }
+
+    /// insert `bytes` at the start of the file
+    pub fn prepend(&mut self, bytes: &[u8]) {
+        self.bytes_mut().splice(0..0, bytes.iter().cloned());
+    }
+
+    /// insert `bytes` before the byte at `offset`
+    ///
+    /// An `offset` equal to the length appends. Unlike writing, inserting
+    /// past the end is an `InvalidInput` error rather than zero filling.
+    pub fn insert_at(&mut self, offset: usize, bytes: &[u8]) -> io::Result<()> {
+        if offset > self.bytes.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                "offset {} is past the end of the file ({} bytes)", offset, self.bytes.len())));
+        }
+        self.bytes_mut().splice(offset..offset, bytes.iter().cloned());
+        Ok(())
+    }
+
+    /// remove a leading UTF-8 or UTF-16 byte order mark, returning whether
+    /// there was one
+    ///
+    /// A UTF-32LE byte order mark (`FF FE 00 00`) starts like a UTF-16LE
+    /// one, so files starting with it are left alone.
+    pub fn strip_bom(&mut self) -> bool {
+        let len = if self.bytes.starts_with(b"\xef\xbb\xbf") {
+            3
+        } else if self.has_utf16_bom() {
+            2
+        } else {
+            return false;
+        };
+        self.bytes_mut().drain(..len);
+        true
+    }
+
+    fn has_utf16_bom(&self) -> bool {
+        self.bytes.starts_with(b"\xfe\xff")
+            || (self.bytes.starts_with(b"\xff\xfe") && !self.bytes.starts_with(b"\xff\xfe\x00\x00"))
+    }
+
+    /// a reference counted handle to the contents, without copying them
+    ///
+    /// The handle stays valid (and unchanged) if the file is later
+    /// modified, since modifying shared contents copies them first.
+    pub fn as_arc(&self) -> Arc<Vec<u8>> {
+        self.bytes.clone()
+    }
+
+    /// a copy of the file which shares its contents with this one
+    pub fn clone_shared(&self) -> File {
+        File {
+            bytes: self.bytes.clone(),
+        }
+    }
+
+    /// the SHA-256 digest of the contents
+    pub fn sha256(&self) -> [u8; 32] {
+        sha256(&self.bytes)
+    }
+
+    /// a 64 bit hash of the contents, stable across runs and platforms
+    ///
+    /// This is the first 8 bytes of the SHA-256 digest.
+    pub fn hash(&self) -> u64 {
+        let digest = self.sha256();
+        let mut first = [0u8; 8];
+        first.copy_from_slice(&digest[..8]);
+        u64::from_be_bytes(first)
+    }
+
+    /// a unified diff (like `diff -u`) from this file to `other`
+    ///
+    /// `path` is used in the `---`/`+++` headers. Returns `None` if the
+    /// files are identical or either is not valid UTF-8. Line endings are
+    /// compared too, so a `\r\n` line differs from the same `\n` line
+    /// and a missing final newline is marked like `diff` does.
+    ///
+    /// Apart from the lines the files start and end with in common, this
+    /// takes time and memory proportional to the product of their line
+    /// counts, so very large files with changes far apart are slow.
+    pub fn unified_diff(&self, other: &File, path: &str) -> Option<String> {
+        if self == other {
+            return None;
+        }
+        let old: Vec<&str> = str::from_utf8(&self.bytes).ok()?.split_inclusive('\n').collect();
+        let new: Vec<&str> = str::from_utf8(&other.bytes).ok()?.split_inclusive('\n').collect();
+        let ops = diff_lines(&old, &new);
+
+        let mut out = format!("--- a/{}\n+++ b/{}\n", path, path);
+        // (old line, new line) reached before each op
+        let mut positions = Vec::with_capacity(ops.len() + 1);
+        let (mut o, mut n) = (0, 0);
+        for op in &ops {
+            positions.push((o, n));
+            match *op {
+                LineOp::Equal(_) => { o += 1; n += 1; }
+                LineOp::Delete(_) => o += 1,
+                LineOp::Insert(_) => n += 1,
+            }
+        }
+        positions.push((o, n));
+
+        let changes: Vec<usize> = (0..ops.len())
+            .filter(|&i| !matches!(ops[i], LineOp::Equal(_)))
+            .collect();
+        let mut i = 0;
+        while i < changes.len() {
+            let start = changes[i].saturating_sub(DIFF_CONTEXT);
+            let mut last = changes[i];
+            while i + 1 < changes.len() && changes[i + 1] - last <= 2 * DIFF_CONTEXT + 1 {
+                i += 1;
+                last = changes[i];
+            }
+            let end = (last + 1 + DIFF_CONTEXT).min(ops.len());
+            let (o_start, n_start) = positions[start];
+            let (o_end, n_end) = positions[end];
+            let header = |start: usize, count: usize| {
+                if count == 0 { format!("{},0", start) } else { format!("{},{}", start + 1, count) }
+            };
+            out.push_str(&format!("@@ -{} +{} @@\n",
+                                  header(o_start, o_end - o_start),
+                                  header(n_start, n_end - n_start)));
+            for op in &ops[start..end] {
+                let (c, line) = match *op {
+                    LineOp::Equal(l) => (' ', l),
+                    LineOp::Delete(l) => ('-', l),
+                    LineOp::Insert(l) => ('+', l),
+                };
+                out.push(c);
+                out.push_str(line);
+                if !line.ends_with('\n') {
+                    out.push_str("\n\\ No newline at end of file\n");
+                }
+            }
+            i += 1;
+        }
+        Some(out)
+    }
+
+    /// the offset of every occurrence of `needle` in the contents
+    ///
+    /// Matches do not overlap: searching continues after the end of each
+    /// match, so `aa` is found in `aaaa` at 0 and 2 only. An empty needle
+    /// matches nowhere.
+    pub fn find_all(&self, needle: &[u8]) -> Vec<usize> {
+        let mut found = Vec::new();
+        if needle.is_empty() {
+            return found;
+        }
+        let mut i = 0;
+        while i + needle.len() <= self.bytes.len() {
+            if &self.bytes[i..i + needle.len()] == needle {
+                found.push(i);
+                i += needle.len();
+            } else {
+                i += 1;
+            }
+        }
+        found
+    }
+
+    /// the number of times each byte value occurs in the contents
+    pub fn byte_histogram(&self) -> [u64; 256] {
+        let mut histogram = [0u64; 256];
+        for &b in self.bytes.iter() {
+            histogram[b as usize] += 1;
+        }
+        histogram
+    }
+
+    /// the Shannon entropy of the contents in bits per byte, from 0.0 to 8.0
+    ///
+    /// Values close to 8.0 suggest the data is already compressed or
+    /// encrypted.
+    pub fn entropy(&self) -> f64 {
+        if self.bytes.is_empty() {
+            return 0.0;
+        }
+        let len = self.bytes.len() as f64;
+        self.byte_histogram().iter()
+            .filter(|&&count| count > 0)
+            .map(|&count| {
+                let p = count as f64 / len;
+                -p * p.log2()
+            })
+            .sum()
+    }
+
+    /// identify the format of the file from its magic bytes
+    pub fn detect_format(&self) -> Option<FileFormat> {
+        FORMAT_SIGNATURES.iter()
+            .find(|&&(_, parts)| parts.iter().all(|&(offset, magic)| {
+                self.bytes.get(offset..offset + magic.len()) == Some(magic)
+            }))
+            .map(|&(format, _)| format)
+    }
+
+    /// the contents encoded as standard, padded base64
+    pub fn to_base64(&self) -> String {
+        let mut out = String::with_capacity(self.bytes.len().div_ceil(3) * 4);
+        for chunk in self.bytes.chunks(3) {
+            let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+            let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+            for i in 0..4 {
+                if i <= chunk.len() {
+                    out.push(BASE64_ALPHABET[((n >> (18 - 6 * i)) & 0x3f) as usize] as char);
+                } else {
+                    out.push('=');
+                }
+            }
+        }
+        out
+    }
+
+    /// decode a file from standard, padded base64
+    ///
+    /// Invalid input is an `InvalidData` error.
+    pub fn from_base64(s: &str) -> io::Result<File> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid base64");
+        let input = s.as_bytes();
+        if !input.len().is_multiple_of(4) {
+            return Err(invalid());
+        }
+        let mut bytes = Vec::with_capacity(input.len() / 4 * 3);
+        for (i, chunk) in input.chunks(4).enumerate() {
+            let last = (i + 1) * 4 == input.len();
+            let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+            if padding > 2 || (padding > 0 && !last) {
+                return Err(invalid());
+            }
+            let mut n = 0u32;
+            for &c in &chunk[..4 - padding] {
+                let v = BASE64_ALPHABET.iter().position(|&a| a == c).ok_or_else(invalid)?;
+                n = (n << 6) | v as u32;
+            }
+            n <<= 6 * padding;
+            bytes.extend_from_slice(&n.to_be_bytes()[1..4 - padding]);
+        }
+        Ok(File::new(bytes))
+    }
+
+    /// gzip the contents, but only if that makes them smaller
+    ///
+    /// Files which look already compressed (see `File::entropy`) are
+    /// stored raw without trying.
+    #[cfg(feature = "flate2")]
+    pub fn maybe_compress(&self) -> StoredBytes {
+        if self.entropy() > COMPRESSED_ENTROPY {
+            return StoredBytes::Raw(self.bytes.clone());
+        }
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        let compressed = encoder.write_all(&self.bytes).and_then(|_| encoder.finish());
+        match compressed {
+            Ok(c) if c.len() < self.bytes.len() => StoredBytes::Compressed(c),
+            _ => StoredBytes::Raw(self.bytes.clone()),
+        }
+    }
+
+    /// guess whether the file holds text rather than binary data
+    ///
+    /// Like git, a file is considered binary if a NUL byte appears in
+    /// its first 8000 bytes.
+    pub fn is_likely_text(&self) -> bool {
+        !self.bytes.iter().take(8000).any(|&b| b == 0)
+    }
+
     pub fn dump(&self, path: &Path) -> io::Result<()> {
         let mut f = fs::File::create(path)?;
         f.write_all(&self.bytes)
     }
 
+    /// dump the file so that readers never see it partially written
+    ///
+    /// The bytes are written to a temporary file in the same directory,
+    /// which is then renamed over `path`. The temporary file always has a
+    /// new name, so concurrent dumps to the same path don't interfere and
+    /// existing files are never overwritten.
+    pub fn dump_atomic(&self, path: &Path) -> io::Result<()> {
+        let name = match path.file_name() {
+            Some(n) => n,
+            None => return Err(io::ErrorKind::InvalidInput.into()),
+        };
+        let (tmp, mut f) = loop {
+            let mut tmp_name = OsString::from(".");
+            tmp_name.push(name);
+            tmp_name.push(format!(".tmp-{}-{}", process::id(),
+                                  TMP_COUNTER.fetch_add(1, Ordering::Relaxed)));
+            let tmp = path.with_file_name(tmp_name);
+            match fs::OpenOptions::new().write(true).create_new(true).open(&tmp) {
+                Ok(f) => break (tmp, f),
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        };
+
+        let result = f.write_all(&self.bytes)
+            .and_then(|_| f.sync_all())
+            .and_then(|_| fs::rename(&tmp, path));
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        result
+    }
+
+}
+
+impl Clone for File {
+    /// a deep copy: the new file does not share its contents
+    fn clone(&self) -> File {
+        File::new(self.bytes.to_vec())
+    }
+}
+
+impl Default for Dir {
+    fn default() -> Dir {
+        Dir::new()
+    }
 }
 
 impl Dir {
@@ -110,6 +741,37 @@ impl Dir {
         Ok(dir)
     }
 
+    /// load only the listed `files`, placing each at its path relative
+    /// to `base`
+    ///
+    /// Only the directories needed to hold the files are created. A file
+    /// which is not inside `base` is an `InvalidInput` error.
+    pub fn load_files(base: &Path, files: &[PathBuf]) -> io::Result<Dir> {
+        let mut dir = Dir::new();
+        for path in files {
+            let rel = path.strip_prefix(base).map_err(|_| io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not inside {}", path.display(), base.display())))?;
+            let name = match rel.file_name() {
+                Some(n) => n.to_os_string(),
+                None => return Err(io::ErrorKind::InvalidInput.into()),
+            };
+            let parent = rel.parent().unwrap_or_else(|| Path::new(""));
+            dir.dir_mut_p(parent)?.add_file(name, File::load(path)?)?;
+        }
+        Ok(dir)
+    }
+
+    /// load the directory at `source` and merge it into this tree at `at`
+    ///
+    /// Missing directories leading to `at` are created.
+    pub fn load_into(&mut self, at: &Path, source: &Path, policy: MergePolicy)
+        -> io::Result<()>
+    {
+        let loaded = Dir::load(source)?;
+        self.dir_mut_p(at)?.merge(loaded, policy)
+    }
+
     pub fn dump(&self, path: &Path) -> io::Result<()> {
         fs::create_dir(path)?;
         for (name, entry) in self.items.iter() {
@@ -119,6 +781,149 @@ impl Dir {
         Ok(())
     }
 
+    /// like `dump`, but write each file through a `BufWriter` with a
+    /// buffer of `buf_size` bytes
+    pub fn dump_buffered(&self, path: &Path, buf_size: usize) -> io::Result<()> {
+        fs::create_dir(path)?;
+        for (name, entry) in self.items.iter() {
+            let epath = path.join(name);
+            match *entry {
+                Entry::File(ref f) => {
+                    let mut w = BufWriter::with_capacity(buf_size, fs::File::create(&epath)?);
+                    w.write_all(&f.bytes)?;
+                    w.flush()?;
+                }
+                Entry::Dir(ref d) => d.dump_buffered(&epath, buf_size)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// like `dump`, but first check every entry against `limits` and
+    /// fail without writing anything if one doesn't pass
+    ///
+    /// Names reported by `fsck` are rejected, except for case collisions
+    /// unless `windows_names` is set. Entries are checked in path order, so
+    /// the error names the first offending path.
+    pub fn dump_checked(&self, path: &Path, limits: DumpLimits) -> Result<(), DumpLimitError> {
+        let illegal = self.fsck().into_iter()
+            .find(|i| limits.windows_names || i.reason != IssueReason::CaseCollision);
+        if let Some(issue) = illegal {
+            return Err(DumpLimitError::IllegalName(issue.path));
+        }
+        let mut paths: Vec<PathBuf> = self.paths().collect();
+        paths.sort();
+        for rel in paths {
+            if let Some(max) = limits.max_depth {
+                if rel.components().count() > max {
+                    return Err(DumpLimitError::TooDeep(rel));
+                }
+            }
+            if let Some(max) = limits.max_path_len {
+                if path.join(&rel).as_os_str().len() > max {
+                    return Err(DumpLimitError::PathTooLong(rel));
+                }
+            }
+            if limits.windows_names {
+                let name = rel.file_name().unwrap_or_default().to_string_lossy();
+                if is_illegal_on_windows(&name) {
+                    return Err(DumpLimitError::IllegalName(rel));
+                }
+            }
+        }
+        self.dump(path)?;
+        Ok(())
+    }
+
+    /// dump the tree onto a possibly partially written copy of it,
+    /// e.g. to continue a dump that was interrupted
+    ///
+    /// Existing directories are reused and files already on disk with the
+    /// expected length are skipped. Use `dump_resume_verified` to also
+    /// compare their contents.
+    pub fn dump_resume(&self, path: &Path) -> io::Result<DumpStats> {
+        let mut stats = DumpStats::default();
+        self.dump_resume_inner(path, false, &mut stats)?;
+        Ok(stats)
+    }
+
+    /// like `dump_resume`, but only skip files whose contents on disk are
+    /// identical
+    pub fn dump_resume_verified(&self, path: &Path) -> io::Result<DumpStats> {
+        let mut stats = DumpStats::default();
+        self.dump_resume_inner(path, true, &mut stats)?;
+        Ok(stats)
+    }
+
+    fn dump_resume_inner(&self, path: &Path, verify: bool, stats: &mut DumpStats)
+        -> io::Result<()>
+    {
+        match fs::metadata(path) {
+            Ok(ref m) if m.is_dir() => {}
+            Ok(_) => return Err(io::ErrorKind::AlreadyExists.into()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => fs::create_dir(path)?,
+            Err(e) => return Err(e),
+        }
+        for (name, entry) in self.items.iter() {
+            let epath = path.join(name);
+            match *entry {
+                Entry::Dir(ref d) => d.dump_resume_inner(&epath, verify, stats)?,
+                Entry::File(ref f) => {
+                    let done = match fs::metadata(&epath) {
+                        Ok(m) => m.is_file() && m.len() == f.bytes.len() as u64,
+                        Err(ref e) if e.kind() == io::ErrorKind::NotFound => false,
+                        Err(e) => return Err(e),
+                    };
+                    if done && (!verify || File::load(&epath)? == *f) {
+                        stats.skipped += 1;
+                    } else {
+                        f.dump(&epath)?;
+                        stats.written += 1;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// set the contents of many files at once, creating them and any
+    /// missing directories as needed
+    ///
+    /// Edits are applied in path order, walking to each directory only
+    /// once. If a path is given twice the last edit wins. Nothing is
+    /// changed if any path is not a relative file path. Otherwise fails on
+    /// the first path which has a file as a parent or is itself a
+    /// directory; edits to earlier paths are kept.
+    pub fn modify_files<I>(&mut self, edits: I) -> io::Result<()>
+        where I: IntoIterator<Item = (PathBuf, Vec<u8>)>
+    {
+        let edits: BTreeMap<PathBuf, Vec<u8>> = edits.into_iter().collect();
+        for path in edits.keys() {
+            let relative = path.components()
+                .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+            if !relative || path.file_name().is_none() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                    "{} is not a relative file path", path.display())));
+            }
+        }
+        let mut edits = edits.into_iter().peekable();
+        while let Some((path, bytes)) = edits.next() {
+            let parent = path.parent().unwrap_or_else(|| Path::new("")).to_path_buf();
+            let dir = self.dir_mut_p(&parent)?;
+            let mut edit = Some((path, bytes));
+            while let Some((path, bytes)) = edit {
+                let name = path.file_name().unwrap().to_os_string();
+                if let Some(&Entry::Dir(_)) = dir.items.get(&name) {
+                    return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!(
+                        "{} is a directory", path.display())));
+                }
+                dir.items.insert(name, Entry::File(File::new(bytes)));
+                edit = edits.next_if(|e| e.0.parent() == Some(parent.as_path()));
+            }
+        }
+        Ok(())
+    }
+
     pub fn add_file(&mut self, name: OsString, file: File) -> io::Result<()> {
         if self.items.contains_key(&name) {
             return Err(io::ErrorKind::AlreadyExists.into());
@@ -135,79 +940,2666 @@ impl Dir {
         Ok(())
     }
 
-    pub fn entries(&self) -> hash_map::Iter<OsString, Entry> {
+    pub fn entries(&self) -> hash_map::Iter<'_, OsString, Entry> {
         self.items.iter()
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::env;
-    use std::fs;
-    use std::str;
-    use std::path::PathBuf;
+    /// the number of top level entries
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
 
-    fn validate_dir(level: usize, dir: &Dir) -> Result<(), String> {
-        for (n, entry) in dir.entries() {
-            let name = n.to_str().unwrap();
-            let (name_prefix, last_c) = name.split_at(name.len()-1);
-            last_c.parse::<usize>().expect(&format!("last_c: {}, invalid name: {}", 
-                                                    last_c, name));
-            match *entry {
-                Entry::File(ref file) => {
-                    if name_prefix != format!("file{}-", level) {
-                        return Err(format!("lvl {} file {} has invalid name", level, name));
-                    }
-                    let data = str::from_utf8(file.bytes()).unwrap();
-                    if data.trim() != name {
-                        return Err(format!("lvl {} file {} has invalid data: \"{}\"", 
-                                           level, name, data));
-                    }
-                }
-                Entry::Dir(ref dir) => {
-                    if name_prefix != format!("dir{}-", level) {
-                        return Err(format!("invalid dir name at level {}: {}", level, name));
-                    }
-                    //println!("validating dir name: {}", name);
-                    validate_dir(level + 1, dir)?;
+    /// whether the dir has no entries
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// get the entry at a path relative to this dir
+    pub fn get(&self, path: &Path) -> Option<&Entry> {
+        let mut dir = self;
+        let mut found: Option<&Entry> = None;
+        for component in path.components() {
+            let name = match component {
+                Component::Normal(n) => n,
+                Component::CurDir => continue,
+                _ => return None,
+            };
+            if let Some(entry) = found {
+                match *entry {
+                    Entry::Dir(ref d) => dir = d,
+                    Entry::File(_) => return None,
                 }
             }
+            found = Some(dir.items.get(name)?);
         }
-        Ok(())
+        found
     }
 
-    fn count_entries(dir: &Dir) -> usize {
-        let mut count = 0;
-        for (_, entry) in dir.entries() {
-            count += 1;
-            if let &Entry::Dir(ref d) = entry {
-                count += count_entries(d);
+    /// the paths of every entry matching `path` when ASCII case is ignored,
+    /// sorted
+    ///
+    /// More than one result means the path is ambiguous on a case
+    /// insensitive filesystem.
+    pub fn get_all_ci(&self, path: &Path) -> Vec<PathBuf> {
+        let mut names = Vec::new();
+        for component in path.components() {
+            match component {
+                Component::Normal(n) => names.push(n),
+                Component::CurDir => {}
+                _ => return Vec::new(),
             }
         }
-        count
+        let mut found = Vec::new();
+        if !names.is_empty() {
+            self.collect_ci(&PathBuf::new(), &names, &mut found);
+        }
+        found.sort();
+        found
     }
 
-    #[test]
-    /// really basic test to just load a directory, validate it, 
-    /// save it to a new folder and reload + validate it
-    fn test() {
-        let cwd = env::current_dir().unwrap();
-        let test_dir: PathBuf = cwd.join(PathBuf::from(
-            file!()).parent().unwrap().to_path_buf());
-        let data_dir: PathBuf = test_dir.join(PathBuf::from("data"));
+    fn collect_ci(&self, prefix: &Path, names: &[&OsStr], out: &mut Vec<PathBuf>) {
+        for (name, entry) in self.items.iter() {
+            if !name.eq_ignore_ascii_case(names[0]) {
+                continue;
+            }
+            let path = prefix.join(name);
+            if names.len() == 1 {
+                out.push(path);
+            } else if let Entry::Dir(ref d) = *entry {
+                d.collect_ci(&path, &names[1..], out);
+            }
+        }
+    }
 
-        println!("loading data dir: {}", data_dir.display());
-        let dir = Dir::load(&data_dir).unwrap();
-        println!("validating data dir: {}", data_dir.display());
-        assert_eq!(count_entries(&dir), 10);
-        validate_dir(0, &dir).expect("data dir");
-        let tmp: PathBuf = test_dir.join(PathBuf::from("test_out_dir"));
+    /// get the directory at `path`, creating it and any missing parents
+    /// (like `mkdir -p`)
+    fn dir_mut_p(&mut self, path: &Path) -> io::Result<&mut Dir> {
+        let mut dir = self;
+        for component in path.components() {
+            let name = match component {
+                Component::Normal(n) => n,
+                Component::CurDir => continue,
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                    "{} is not a relative path", path.display()))),
+            };
+            let entry = dir.items.entry(name.to_os_string()).or_insert_with(Entry::empty_dir);
+            dir = match *entry {
+                Entry::Dir(ref mut d) => d,
+                Entry::File(_) => return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!(
+                    "{} is a file in {}", name.to_string_lossy(), path.display()))),
+            };
+        }
+        Ok(dir)
+    }
+
+    /// iterate over the relative paths of every file and directory in the
+    /// tree, in no particular order
+    ///
+    /// A directory is always yielded before its contents.
+    pub fn paths(&self) -> Paths<'_> {
+        Paths {
+            stack: vec![(PathBuf::new(), self.items.iter())],
+        }
+    }
+
+    /// iterate over the top level entries accepted by the predicate
+    pub fn entries_where<F>(&self, f: F) -> impl Iterator<Item = (&OsString, &Entry)>
+        where F: Fn(&OsStr, &Entry) -> bool
+    {
+        self.items.iter().filter(move |&(name, entry)| f(name, entry))
+    }
+
+    /// the number of filesystem objects `dump` creates for this tree,
+    /// counting the root directory itself
+    pub fn object_count(&self) -> usize {
+        let mut count = 1;
+        for entry in self.items.values() {
+            count += match *entry {
+                Entry::File(_) => 1,
+                Entry::Dir(ref d) => d.object_count(),
+            };
+        }
+        count
+    }
+
+    /// replace every match of the regex `pattern` in the contents of each
+    /// text file, returning the total number of replacements made
+    ///
+    /// `replacement` may refer to capture groups, i.e. `$1` or `${name}`.
+    /// Binary files (see `File::is_likely_text`) are left untouched.
+    #[cfg(feature = "regex")]
+    pub fn replace_regex(&mut self, pattern: &str, replacement: &str) -> io::Result<usize> {
+        self.replace_regex_with(pattern, replacement, LikelyText)
+    }
+
+    /// like `replace_regex`, with `classifier` deciding which files are
+    /// text
+    #[cfg(feature = "regex")]
+    pub fn replace_regex_with<C>(&mut self, pattern: &str, replacement: &str, classifier: C)
+        -> io::Result<usize>
+        where C: TextClassifier
+    {
+        let re = regex::bytes::Regex::new(pattern)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mut count = 0;
+        self.visit_files_mut(Path::new(""), &mut |path, file| {
+            if !classifier.is_text(path, file) {
+                return;
+            }
+            let found = re.find_iter(&file.bytes).count();
+            if found > 0 {
+                let replaced = re.replace_all(&file.bytes, replacement.as_bytes()).into_owned();
+                file.bytes = Arc::new(replaced);
+                count += found;
+            }
+        });
+        Ok(count)
+    }
+
+    /// move every file whose relative path (with `/` separators) matches
+    /// the regex `pattern`, replacing the first match with `replacement`
+    ///
+    /// `replacement` may refer to capture groups, so `src/(.*)/mod.rs`
+    /// and `src/$1.rs` flattens modules. Missing directories are created
+    /// and directories left empty are kept. Returns the number of files
+    /// moved. If two files would end up at the same path, or a file would
+    /// land on an existing entry, nothing is moved and an `AlreadyExists`
+    /// error naming both paths is returned. Paths which are not valid
+    /// UTF-8 are never matched.
+    #[cfg(feature = "regex")]
+    pub fn rename_paths_regex(&mut self, pattern: &str, replacement: &str) -> io::Result<usize> {
+        let re = regex::Regex::new(pattern)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mut moves = Vec::new();
+        for (path, _) in self.files_sorted() {
+            let components: Option<Vec<&str>> = path.iter().map(|c| c.to_str()).collect();
+            let old = match components {
+                Some(c) => c.join("/"),
+                None => continue,
+            };
+            let new = re.replace(&old, replacement);
+            if new != old {
+                moves.push((path, PathBuf::from(new.into_owned())));
+            }
+        }
+
+        let sources: HashSet<&Path> = moves.iter().map(|m| m.0.as_path()).collect();
+        let mut targets: HashMap<&Path, &Path> = HashMap::new();
+        for (from, to) in &moves {
+            let normal = to.components().all(|c| matches!(c, Component::Normal(_)));
+            if to.as_os_str().is_empty() || !normal {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                    "{} would move to invalid path {}", from.display(), to.display())));
+            }
+            if let Some(other) = targets.insert(to, from) {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!(
+                    "{} and {} would both move to {}",
+                    other.display(), from.display(), to.display())));
+            }
+            // the target, or one of its parents if that is a file, must be
+            // free once the moves are done
+            for existing in to.ancestors().filter(|p| !p.as_os_str().is_empty()) {
+                let blocks = match self.get(existing) {
+                    Some(&Entry::File(_)) => !sources.contains(existing),
+                    Some(&Entry::Dir(_)) => existing == to.as_path(),
+                    None => false,
+                };
+                if blocks {
+                    return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!(
+                        "{} would move onto existing {}", from.display(), existing.display())));
+                }
+            }
+        }
+
+        for (from, to) in &moves {
+            if let Some(other) = to.ancestors().skip(1).find_map(|p| targets.get(p)) {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!(
+                    "{} would move inside {}, which is a file", from.display(), other.display())));
+            }
+        }
+
+        let mut taken = Vec::with_capacity(moves.len());
+        for (from, to) in moves {
+            let parent = self.dir_mut_p(from.parent().unwrap_or_else(|| Path::new("")))?;
+            let entry = parent.items.remove(from.file_name().unwrap()).unwrap();
+            taken.push((to, entry));
+        }
+        let count = taken.len();
+        for (to, entry) in taken {
+            let parent = self.dir_mut_p(to.parent().unwrap_or_else(|| Path::new("")))?;
+            parent.items.insert(to.file_name().unwrap().to_os_string(), entry);
+        }
+        Ok(count)
+    }
+
+    /// convert `\r\n` line endings to `\n` in every text file, returning
+    /// how many files were changed
+    ///
+    /// Binary files (see `File::is_likely_text`) are left untouched.
+    pub fn normalize_line_endings(&mut self) -> usize {
+        self.normalize_line_endings_with(LikelyText)
+    }
+
+    /// like `normalize_line_endings`, with `classifier` deciding which
+    /// files are text
+    pub fn normalize_line_endings_with<C: TextClassifier>(&mut self, classifier: C) -> usize {
+        let mut count = 0;
+        self.visit_files_mut(Path::new(""), &mut |path, file| {
+            if !classifier.is_text(path, file) || !file.bytes.windows(2).any(|w| w == b"\r\n") {
+                return;
+            }
+            let mut normalized = Vec::with_capacity(file.bytes.len());
+            for (i, &b) in file.bytes.iter().enumerate() {
+                if b != b'\r' || file.bytes.get(i + 1) != Some(&b'\n') {
+                    normalized.push(b);
+                }
+            }
+            file.bytes = Arc::new(normalized);
+            count += 1;
+        });
+        count
+    }
+
+    /// remove the byte order mark (see `File::strip_bom`) from every text
+    /// file, returning how many were stripped
+    ///
+    /// UTF-16 text usually contains NUL bytes, so a file starting with a
+    /// UTF-16 byte order mark counts as text.
+    pub fn strip_boms(&mut self) -> usize {
+        self.strip_boms_with(|path: &Path, file: &File| {
+            file.has_utf16_bom() || LikelyText.is_text(path, file)
+        })
+    }
+
+    /// like `strip_boms`, with `classifier` deciding which files are text
+    pub fn strip_boms_with<C: TextClassifier>(&mut self, classifier: C) -> usize {
+        let mut count = 0;
+        self.visit_files_mut(Path::new(""), &mut |path, file| {
+            if classifier.is_text(path, file) && file.strip_bom() {
+                count += 1;
+            }
+        });
+        count
+    }
+
+    /// append a `\n` to every non-empty text file that does not already
+    /// end with one, returning how many files were changed
+    ///
+    /// Empty and binary files (see `File::is_likely_text`) are left
+    /// untouched.
+    pub fn ensure_trailing_newline(&mut self) -> usize {
+        self.ensure_trailing_newline_with(LikelyText)
+    }
+
+    /// like `ensure_trailing_newline`, with `classifier` deciding which
+    /// files are text
+    pub fn ensure_trailing_newline_with<C: TextClassifier>(&mut self, classifier: C) -> usize {
+        let mut count = 0;
+        self.visit_files_mut(Path::new(""), &mut |path, file| {
+            match file.bytes.last() {
+                Some(&b'\n') | None => return,
+                Some(_) => {}
+            }
+            if classifier.is_text(path, file) {
+                file.bytes_mut().push(b'\n');
+                count += 1;
+            }
+        });
+        count
+    }
+
+    /// remove every file in the tree whose contents match the predicate,
+    /// returning how many were removed
+    ///
+    /// Directories left empty are kept, use `prune_empty` to remove them.
+    pub fn remove_files_where<F: Fn(&File) -> bool>(&mut self, f: F) -> usize {
+        self.remove_files_inner(&f)
+    }
+
+    fn remove_files_inner<F: Fn(&File) -> bool>(&mut self, f: &F) -> usize {
+        let before = self.items.len();
+        self.items.retain(|_, entry| match *entry {
+            Entry::File(ref file) => !f(file),
+            Entry::Dir(_) => true,
+        });
+        let mut removed = before - self.items.len();
+        for entry in self.items.values_mut() {
+            if let Entry::Dir(ref mut d) = *entry {
+                removed += d.remove_files_inner(f);
+            }
+        }
+        removed
+    }
+
+    /// remove every directory below this one which is empty or only holds
+    /// empty directories, returning how many were removed
+    pub fn prune_empty(&mut self) -> usize {
+        let mut removed = 0;
+        for entry in self.items.values_mut() {
+            if let Entry::Dir(ref mut d) = *entry {
+                removed += d.prune_empty();
+            }
+        }
+        let before = self.items.len();
+        self.items.retain(|_, entry| match *entry {
+            Entry::Dir(ref d) => !d.is_empty(),
+            Entry::File(_) => true,
+        });
+        removed + before - self.items.len()
+    }
+
+    /// call `f` with the path (relative to this dir) of every file in the tree
+    fn visit_files_mut<F>(&mut self, prefix: &Path, f: &mut F)
+        where F: FnMut(&Path, &mut File)
+    {
+        for (name, entry) in self.items.iter_mut() {
+            let path = prefix.join(name);
+            match *entry {
+                Entry::File(ref mut file) => f(&path, file),
+                Entry::Dir(ref mut dir) => dir.visit_files_mut(&path, f),
+            }
+        }
+    }
+
+    /// a copy of the tree whose files share their contents with this one
+    ///
+    /// This is much cheaper than `clone` for large trees. Shared contents
+    /// are copied the first time either tree modifies them.
+    pub fn clone_shared(&self) -> Dir {
+        let items = self.items.iter()
+            .map(|(name, entry)| (name.clone(), entry.clone_shared()))
+            .collect();
+        Dir { items }
+    }
+
+    /// dump the tree, plus a manifest file at its root listing the SHA-256
+    /// of every file
+    ///
+    /// Each line of the manifest is `<hex digest>  <relative/path>`, the
+    /// format understood by `sha256sum -c`. The manifest does not list
+    /// itself. Like `sha256sum`, a path containing `\`, `\n` or `\r` is
+    /// escaped and its line starts with `\`.
+    ///
+    /// Nothing is written if `manifest_name` is not a plain file name, is
+    /// already used at the root, or a path in the tree is not valid UTF-8.
+    pub fn dump_with_manifest(&self, path: &Path, manifest_name: &str) -> io::Result<()> {
+        let mut components = Path::new(manifest_name).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) => {}
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                "{:?} is not a valid manifest name", manifest_name))),
+        }
+        if self.items.contains_key(OsStr::new(manifest_name)) {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        let mut manifest = String::new();
+        for (fpath, file) in self.files_sorted() {
+            let mut components = Vec::new();
+            for c in fpath.iter() {
+                components.push(c.to_str().ok_or_else(|| io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} is not valid UTF-8", fpath.display())))?);
+            }
+            let rel = components.join("/");
+            if rel.contains(['\\', '\n', '\r']) {
+                manifest.push('\\');
+            }
+            for b in file.sha256().iter() {
+                manifest.push_str(&format!("{:02x}", b));
+            }
+            manifest.push_str("  ");
+            for c in rel.chars() {
+                match c {
+                    '\\' => manifest.push_str("\\\\"),
+                    '\n' => manifest.push_str("\\n"),
+                    '\r' => manifest.push_str("\\r"),
+                    c => manifest.push(c),
+                }
+            }
+            manifest.push('\n');
+        }
+        self.dump(path)?;
+        File::new(manifest.into_bytes()).dump(&path.join(manifest_name))
+    }
+
+    /// every file in the tree with its relative path, sorted by path
+    fn files_sorted(&self) -> Vec<(PathBuf, &File)> {
+        let mut files = Vec::new();
+        self.collect_files(Path::new(""), &mut files);
+        files.sort_by(|a, b| a.0.cmp(&b.0));
+        files
+    }
+
+    fn collect_files<'a>(&'a self, prefix: &Path, out: &mut Vec<(PathBuf, &'a File)>) {
+        for (name, entry) in self.items.iter() {
+            let path = prefix.join(name);
+            match *entry {
+                Entry::File(ref f) => out.push((path, f)),
+                Entry::Dir(ref d) => d.collect_files(&path, out),
+            }
+        }
+    }
+
+    /// move the whole tree into the (possibly nested) directory `prefix`
+    ///
+    /// Only the normal components of `prefix` are used, so `pkg/./v1`
+    /// and `/pkg/v1` both behave like `pkg/v1`. A prefix containing `..`
+    /// (or a windows drive) is an `InvalidInput` error.
+    pub fn under_prefix(self, prefix: &Path) -> io::Result<Dir> {
+        let mut names = Vec::new();
+        for component in prefix.components() {
+            match component {
+                Component::Normal(n) => names.push(n.to_os_string()),
+                Component::CurDir | Component::RootDir => {}
+                Component::ParentDir | Component::Prefix(_) => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                        "{} can't be used as a prefix", prefix.display())));
+                }
+            }
+        }
+        Ok(names.into_iter().rev().fold(self, |inner, name| {
+            let mut outer = Dir::new();
+            outer.items.insert(name, Entry::Dir(inner));
+            outer
+        }))
+    }
+
+    /// split the tree into its top level directories and its top level
+    /// files
+    pub fn split_top_level(self) -> (HashMap<OsString, Dir>, Vec<(OsString, File)>) {
+        let mut dirs = HashMap::new();
+        let mut files = Vec::new();
+        for (name, entry) in self.items {
+            match entry {
+                Entry::Dir(d) => {
+                    dirs.insert(name, d);
+                }
+                Entry::File(f) => files.push((name, f)),
+            }
+        }
+        (dirs, files)
+    }
+
+    /// the number of distinct file contents in the tree (by `File::hash`)
+    pub fn distinct_content_count(&self) -> usize {
+        let mut files = Vec::new();
+        self.collect_files(Path::new(""), &mut files);
+        files.iter().map(|&(_, f)| f.hash()).collect::<HashSet<_>>().len()
+    }
+
+    /// collect `TreeStats` in a single walk of the tree
+    pub fn stats(&self) -> TreeStats {
+        let mut stats = TreeStats::default();
+        self.stats_inner(Path::new(""), 1, &mut stats);
+        stats
+    }
+
+    fn stats_inner(&self, prefix: &Path, depth: usize, stats: &mut TreeStats) {
+        if !self.items.is_empty() {
+            stats.max_depth = stats.max_depth.max(depth);
+        }
+        for (name, entry) in self.items.iter() {
+            let path = prefix.join(name);
+            match *entry {
+                Entry::Dir(ref d) => {
+                    stats.dirs += 1;
+                    d.stats_inner(&path, depth + 1, stats);
+                }
+                Entry::File(ref f) => {
+                    let size = f.bytes.len() as u64;
+                    stats.files += 1;
+                    stats.bytes += size;
+                    let bucket = (64 - size.leading_zeros()) as usize;
+                    if stats.size_histogram.len() <= bucket {
+                        stats.size_histogram.resize(bucket + 1, 0);
+                    }
+                    stats.size_histogram[bucket] += 1;
+                    let larger = match stats.largest_file {
+                        Some((ref lpath, lsize)) => size > lsize || (size == lsize && path < *lpath),
+                        None => true,
+                    };
+                    if larger {
+                        stats.largest_file = Some((path, size));
+                    }
+                }
+            }
+        }
+    }
+
+    /// the files whose `File::hash` is not in `known_hashes`, sorted by
+    /// path
+    pub fn new_files_versus(&self, known_hashes: &HashSet<u64>) -> Vec<(PathBuf, &File)> {
+        self.files_sorted().into_iter()
+            .filter(|&(_, f)| !known_hashes.contains(&f.hash()))
+            .collect()
+    }
+
+    /// the total number of bytes in all files in the tree
+    pub fn total_size(&self) -> u64 {
+        self.items.values().map(Entry::size).sum()
+    }
+
+    /// split the top level entries between `n` new trees so that their
+    /// `total_size`s are as even as possible
+    ///
+    /// Each top level entry goes whole into one shard, largest first into
+    /// the currently smallest shard. Panics if `n` is 0.
+    pub fn shard(self, n: usize) -> Vec<Dir> {
+        assert!(n > 0, "can't shard into 0 dirs");
+        let mut entries: Vec<_> = self.items.into_iter()
+            .map(|(name, entry)| (entry.size(), name, entry))
+            .collect();
+        entries.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+
+        let mut shards: Vec<(u64, Dir)> = (0..n).map(|_| (0, Dir::new())).collect();
+        for (size, name, entry) in entries {
+            let smallest = shards.iter_mut().min_by_key(|s| s.0).unwrap();
+            smallest.0 += size;
+            smallest.1.items.insert(name, entry);
+        }
+        shards.into_iter().map(|s| s.1).collect()
+    }
+
+    /// the `n` directories below this one with the largest `total_size`,
+    /// largest first (ties are sorted by path)
+    pub fn largest_dirs(&self, n: usize) -> Vec<(PathBuf, u64)> {
+        let mut dirs = Vec::new();
+        self.collect_dir_sizes(Path::new(""), &mut dirs);
+        dirs.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        dirs.truncate(n);
+        dirs
+    }
+
+    /// push the size of every subdirectory, returning the size of this one
+    fn collect_dir_sizes(&self, prefix: &Path, out: &mut Vec<(PathBuf, u64)>) -> u64 {
+        let mut size = 0;
+        for (name, entry) in self.items.iter() {
+            size += match *entry {
+                Entry::File(ref f) => f.bytes.len() as u64,
+                Entry::Dir(ref d) => {
+                    let path = prefix.join(name);
+                    let dsize = d.collect_dir_sizes(&path, out);
+                    out.push((path, dsize));
+                    dsize
+                }
+            };
+        }
+        size
+    }
+
+    /// render the tree like the `tree` command, with entries sorted by name
+    ///
+    /// ```text
+    /// .
+    /// ├── dir
+    /// │   └── file (4 bytes)
+    /// └── other (10 bytes)
+    /// ```
+    pub fn tree_report(&self, opts: TreeReportOpts) -> String {
+        let mut out = String::from(".");
+        if opts.dir_sizes {
+            out.push_str(&format!(" ({} bytes)", self.total_size()));
+        }
+        out.push('\n');
+        let (dirs, files) = self.tree_report_inner(&opts, "", &mut out);
+        if opts.summary {
+            out.push_str(&format!("\n{} directories, {} files, {} bytes\n",
+                                  dirs, files, self.total_size()));
+        }
+        out
+    }
+
+    /// render the entries below `indent`, returning the (dirs, files) seen
+    fn tree_report_inner(&self, opts: &TreeReportOpts, indent: &str, out: &mut String)
+        -> (usize, usize)
+    {
+        let mut names: Vec<_> = self.items.keys().collect();
+        names.sort();
+        let (mut dirs, mut files) = (0, 0);
+        for (i, name) in names.iter().enumerate() {
+            let last = i + 1 == names.len();
+            out.push_str(indent);
+            out.push_str(if last { "└── " } else { "├── " });
+            out.push_str(&name.to_string_lossy());
+            match self.items[*name] {
+                Entry::File(ref f) => {
+                    files += 1;
+                    if opts.file_sizes {
+                        out.push_str(&format!(" ({} bytes)", f.bytes.len()));
+                    }
+                    out.push('\n');
+                }
+                Entry::Dir(ref d) => {
+                    dirs += 1;
+                    if opts.dir_sizes {
+                        out.push_str(&format!(" ({} bytes)", d.total_size()));
+                    }
+                    out.push('\n');
+                    let indent = format!("{}{}", indent, if last { "    " } else { "│   " });
+                    let (d_dirs, d_files) = d.tree_report_inner(opts, &indent, out);
+                    dirs += d_dirs;
+                    files += d_files;
+                }
+            }
+        }
+        (dirs, files)
+    }
+
+    /// check the tree for entries which can't be dumped to a filesystem
+    /// correctly, sorted by path
+    ///
+    /// An entry may be reported more than once for different reasons.
+    pub fn fsck(&self) -> Vec<Issue> {
+        let mut issues = Vec::new();
+        self.fsck_inner(Path::new(""), &mut issues);
+        issues.sort_by(|a, b| a.path.cmp(&b.path));
+        issues
+    }
+
+    fn fsck_inner(&self, prefix: &Path, out: &mut Vec<Issue>) {
+        let mut folded: HashMap<OsString, usize> = HashMap::new();
+        for name in self.items.keys() {
+            *folded.entry(name.to_ascii_lowercase()).or_insert(0) += 1;
+        }
+        for (name, entry) in self.items.iter() {
+            // a name like "a/b" can't be joined onto the path as is
+            let mut path = prefix.as_os_str().to_os_string();
+            if !path.is_empty() {
+                path.push("/");
+            }
+            path.push(name);
+            let path = PathBuf::from(path);
+
+            let lossy = name.to_string_lossy();
+            let mut reasons = Vec::new();
+            if lossy.is_empty() {
+                reasons.push(IssueReason::EmptyName);
+            }
+            if lossy == "." || lossy == ".." {
+                reasons.push(IssueReason::DotName);
+            }
+            if lossy.contains('/') || (cfg!(windows) && lossy.contains('\\')) {
+                reasons.push(IssueReason::Separator);
+            }
+            if lossy.contains('\0') {
+                reasons.push(IssueReason::Nul);
+            }
+            if folded[&name.to_ascii_lowercase()] > 1 {
+                reasons.push(IssueReason::CaseCollision);
+            }
+            for reason in reasons {
+                out.push(Issue { path: path.clone(), reason });
+            }
+            if let Entry::Dir(ref d) = *entry {
+                d.fsck_inner(&path, out);
+            }
+        }
+    }
+
+    /// every directory (including this one, as the empty path) with more
+    /// than `max_children` direct entries, along with its entry count
+    ///
+    /// The result is sorted by path.
+    pub fn dirs_exceeding(&self, max_children: usize) -> Vec<(PathBuf, usize)> {
+        let mut found = Vec::new();
+        self.collect_exceeding(&PathBuf::new(), max_children, &mut found);
+        found.sort();
+        found
+    }
+
+    fn collect_exceeding(&self, path: &Path, max: usize, out: &mut Vec<(PathBuf, usize)>) {
+        if self.items.len() > max {
+            out.push((path.to_path_buf(), self.items.len()));
+        }
+        for (name, entry) in self.items.iter() {
+            if let Entry::Dir(ref d) = *entry {
+                d.collect_exceeding(&path.join(name), max, out);
+            }
+        }
+    }
+
+    /// the differences going from this tree to `other`, sorted by path
+    ///
+    /// A directory only present on one side is reported once, not once
+    /// per entry inside it. An entry which is a file on one side and a
+    /// directory on the other is reported as `Removed` and then `Added`.
+    pub fn diff(&self, other: &Dir) -> Vec<Diff> {
+        let mut diffs = Vec::new();
+        self.diff_inner(other, &|a: &File, b: &File| a == b, Path::new(""), &mut diffs);
+        diffs
+    }
+
+    /// like `diff`, but files are compared after passing their contents
+    /// through `normalize`
+    ///
+    /// This makes it possible to ignore cosmetic changes, e.g. trailing
+    /// whitespace or line endings.
+    pub fn diff_normalized<F>(&self, other: &Dir, normalize: F) -> Vec<Diff>
+        where F: Fn(&[u8]) -> Vec<u8>
+    {
+        let mut diffs = Vec::new();
+        let same = |a: &File, b: &File| a == b || normalize(a.bytes()) == normalize(b.bytes());
+        self.diff_inner(other, &same, Path::new(""), &mut diffs);
+        diffs
+    }
+
+    /// like `diff`, but compute the differences lazily while iterating
+    ///
+    /// The differences come in the same (sorted) order as from `diff`.
+    pub fn diff_stream<'a>(&'a self, other: &'a Dir) -> DiffStream<'a> {
+        DiffStream {
+            stack: vec![DiffFrame::new(PathBuf::new(), self, other)],
+            pending: None,
+        }
+    }
+
+    /// the differences going from this tree to the directory at `path`,
+    /// the same as `self.diff(&Dir::load(path)?)` without loading it
+    ///
+    /// A file on disk is only read when its size matches the file in
+    /// memory.
+    pub fn differs_from_disk(&self, path: &Path) -> io::Result<Vec<Diff>> {
+        let mut out = Vec::new();
+        self.differs_from_disk_inner(path, Path::new(""), &mut out)?;
+        Ok(out)
+    }
+
+    fn differs_from_disk_inner(&self, path: &Path, prefix: &Path, out: &mut Vec<Diff>)
+        -> io::Result<()>
+    {
+        let mut on_disk = BTreeMap::new();
+        for e in fs::read_dir(path)? {
+            let entry = e?;
+            let ftype = entry.file_type()?;
+            if !ftype.is_dir() && !ftype.is_file() {
+                return Err(io::ErrorKind::Other.into());
+            }
+            on_disk.insert(entry.file_name(), (ftype.is_dir(), entry.metadata()?.len()));
+        }
+        let names: BTreeSet<&OsString> = self.items.keys().chain(on_disk.keys()).collect();
+        for name in names {
+            let rel = prefix.join(name);
+            match (self.items.get(name), on_disk.get(name)) {
+                (Some(Entry::File(f)), Some(&(false, len))) => {
+                    if f.bytes.len() as u64 != len || fs::read(path.join(name))? != *f.bytes {
+                        out.push(Diff::ModifiedFile(rel));
+                    }
+                }
+                (Some(Entry::Dir(d)), Some(&(true, _))) => {
+                    d.differs_from_disk_inner(&path.join(name), &rel, out)?;
+                }
+                (Some(_), Some(_)) => {
+                    out.push(Diff::Removed(rel.clone()));
+                    out.push(Diff::Added(rel));
+                }
+                (Some(_), None) => out.push(Diff::Removed(rel)),
+                (None, Some(_)) => out.push(Diff::Added(rel)),
+                (None, None) => unreachable!(),
+            }
+        }
+        Ok(())
+    }
+
+    /// the paths (added, removed) going from this tree to `other`, without
+    /// comparing file contents
+    ///
+    /// As with `diff`, a directory only present on one side is reported
+    /// by itself and a change between file and directory is both removed
+    /// and added.
+    pub fn diff_structure(&self, other: &Dir) -> (Vec<PathBuf>, Vec<PathBuf>) {
+        let mut diffs = Vec::new();
+        self.diff_inner(other, &|_: &File, _: &File| true, Path::new(""), &mut diffs);
+        let mut added = Vec::new();
+        let mut removed = Vec::new();
+        for diff in diffs {
+            match diff {
+                Diff::Added(p) => added.push(p),
+                Diff::Removed(p) => removed.push(p),
+                Diff::ModifiedFile(_) => unreachable!(),
+            }
+        }
+        (added, removed)
+    }
+
+    fn diff_inner<F>(&self, other: &Dir, same: &F, prefix: &Path, out: &mut Vec<Diff>)
+        where F: Fn(&File, &File) -> bool
+    {
+        let names: BTreeSet<&OsString> = self.items.keys().chain(other.items.keys()).collect();
+        for name in names {
+            let path = prefix.join(name);
+            match (self.items.get(name), other.items.get(name)) {
+                (Some(Entry::File(a)), Some(Entry::File(b))) => {
+                    if !same(a, b) {
+                        out.push(Diff::ModifiedFile(path));
+                    }
+                }
+                (Some(Entry::Dir(a)), Some(Entry::Dir(b))) => a.diff_inner(b, same, &path, out),
+                (Some(_), Some(_)) => {
+                    out.push(Diff::Removed(path.clone()));
+                    out.push(Diff::Added(path));
+                }
+                (Some(_), None) => out.push(Diff::Removed(path)),
+                (None, Some(_)) => out.push(Diff::Added(path)),
+                (None, None) => unreachable!(),
+            }
+        }
+    }
+
+    /// move all entries of `other` into this tree
+    ///
+    /// Directories present in both are merged recursively, any other
+    /// collision is resolved by `policy`. With `MergePolicy::Error` this
+    /// tree is left unchanged if anything collides.
+    pub fn merge(&mut self, other: Dir, policy: MergePolicy) -> io::Result<()> {
+        if policy == MergePolicy::Error {
+            if let Some(path) = self.merge_preview(&other, policy).conflicts.first() {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!(
+                    "{} already exists", path.display())));
+            }
+        }
+        self.merge_inner(other, policy);
+        Ok(())
+    }
+
+    /// what merging `other` into this tree with `policy` would do,
+    /// without changing either tree
+    pub fn merge_preview(&self, other: &Dir, policy: MergePolicy) -> MergePlan {
+        let mut plan = MergePlan::default();
+        self.merge_preview_inner(other, policy, Path::new(""), &mut plan);
+        plan.added.sort();
+        plan.overwritten.sort();
+        plan.skipped.sort();
+        plan.conflicts.sort();
+        plan
+    }
+
+    fn merge_preview_inner(&self, other: &Dir, policy: MergePolicy, prefix: &Path,
+                           plan: &mut MergePlan) {
+        for (name, entry) in other.items.iter() {
+            let existing = self.items.get(name);
+            let path = prefix.join(name);
+            match policy.action(existing, entry) {
+                MergeAction::Add => plan.added.push(path),
+                MergeAction::Overwrite => plan.overwritten.push(path),
+                MergeAction::Skip => plan.skipped.push(path),
+                MergeAction::Conflict => plan.conflicts.push(path),
+                MergeAction::Recurse => {
+                    if let (Some(Entry::Dir(d)), Entry::Dir(o)) = (existing, entry) {
+                        d.merge_preview_inner(o, policy, &path, plan);
+                    }
+                }
+            }
+        }
+    }
+
+    fn merge_inner(&mut self, other: Dir, policy: MergePolicy) {
+        for (name, entry) in other.items {
+            match policy.action(self.items.get(&name), &entry) {
+                MergeAction::Add | MergeAction::Overwrite => {
+                    self.items.insert(name, entry);
+                }
+                MergeAction::Recurse => {
+                    if let (Some(Entry::Dir(d)), Entry::Dir(o)) = (self.items.get_mut(&name), entry) {
+                        d.merge_inner(o, policy);
+                    }
+                }
+                MergeAction::Skip | MergeAction::Conflict => {}
+            }
+        }
+    }
+
+    /// move all entries of `other` into this tree, appending the contents
+    /// of colliding files
+    ///
+    /// Where both trees have a file at the same path the result is our
+    /// bytes, then `separator`, then theirs. Directories present in both
+    /// are merged recursively. A file colliding with a directory is an
+    /// error, in which case this tree is left unchanged.
+    pub fn merge_concat(&mut self, other: Dir, separator: &[u8]) -> io::Result<()> {
+        if let Some(path) = self.concat_conflict(&other, Path::new("")) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!(
+                "{} is both a file and a directory", path.display())));
+        }
+        self.merge_concat_inner(other, separator);
+        Ok(())
+    }
+
+    fn concat_conflict(&self, other: &Dir, prefix: &Path) -> Option<PathBuf> {
+        for (name, entry) in other.items.iter() {
+            match (self.items.get(name), entry) {
+                (None, _) | (Some(Entry::File(_)), Entry::File(_)) => {}
+                (Some(Entry::Dir(a)), Entry::Dir(b)) => {
+                    if let Some(path) = a.concat_conflict(b, &prefix.join(name)) {
+                        return Some(path);
+                    }
+                }
+                (Some(_), _) => return Some(prefix.join(name)),
+            }
+        }
+        None
+    }
+
+    fn merge_concat_inner(&mut self, other: Dir, separator: &[u8]) {
+        for (name, entry) in other.items {
+            match (self.items.get_mut(&name), entry) {
+                (Some(Entry::File(a)), Entry::File(b)) => {
+                    let bytes = a.bytes_mut();
+                    bytes.extend_from_slice(separator);
+                    bytes.extend_from_slice(b.bytes());
+                }
+                (Some(Entry::Dir(a)), Entry::Dir(b)) => a.merge_concat_inner(b, separator),
+                (_, entry) => {
+                    self.items.insert(name, entry);
+                }
+            }
+        }
+    }
+
+    /// a tree of the files at the same path with identical contents in
+    /// both trees
+    ///
+    /// Only the directories needed to hold those files are included.
+    pub fn intersection(&self, other: &Dir) -> Dir {
+        let mut common = Dir::new();
+        for (name, entry) in self.items.iter() {
+            let shared = match (entry, other.items.get(name)) {
+                (Entry::File(a), Some(Entry::File(b))) if a == b => Entry::File(a.clone_shared()),
+                (Entry::Dir(a), Some(Entry::Dir(b))) => {
+                    let d = a.intersection(b);
+                    if d.is_empty() {
+                        continue;
+                    }
+                    Entry::Dir(d)
+                }
+                _ => continue,
+            };
+            common.items.insert(name.clone(), shared);
+        }
+        common
+    }
+
+    /// three way merge of `ours` and `theirs`, which both derive from `base`
+    ///
+    /// A change made on only one side is kept, as is a change made
+    /// identically on both. Where the sides changed a path differently
+    /// (including one side removing what the other modified) a `Conflict`
+    /// is reported and our version is kept in the merged tree.
+    pub fn merge3(base: &Dir, ours: &Dir, theirs: &Dir) -> (Dir, Vec<Conflict>) {
+        let mut conflicts = Vec::new();
+        let merged = Dir::merge3_inner(base, ours, theirs, Path::new(""), &mut conflicts);
+        (merged, conflicts)
+    }
+
+    fn merge3_inner(base: &Dir, ours: &Dir, theirs: &Dir, prefix: &Path,
+                    conflicts: &mut Vec<Conflict>) -> Dir {
+        let names: BTreeSet<&OsString> = base.items.keys()
+            .chain(ours.items.keys())
+            .chain(theirs.items.keys())
+            .collect();
+        let empty = Dir::new();
+        let mut merged = Dir::new();
+        for name in names {
+            let b = base.items.get(name);
+            let o = ours.items.get(name);
+            let t = theirs.items.get(name);
+            let entry = if o == t || t == b {
+                o.map(Entry::clone_shared)
+            } else if o == b {
+                t.map(Entry::clone_shared)
+            } else if let (Some(Entry::Dir(od)), Some(Entry::Dir(td))) = (o, t) {
+                let bd = match b {
+                    Some(Entry::Dir(d)) => d,
+                    _ => &empty,
+                };
+                let path = prefix.join(name);
+                Some(Entry::Dir(Dir::merge3_inner(bd, od, td, &path, conflicts)))
+            } else {
+                conflicts.push(Conflict { path: prefix.join(name) });
+                o.map(Entry::clone_shared)
+            };
+            if let Some(entry) = entry {
+                merged.items.insert(name.clone(), entry);
+            }
+        }
+        merged
+    }
+
+    /// start a transaction: edits made through it can be undone as a whole
+    pub fn begin(&mut self) -> Transaction<'_> {
+        Transaction {
+            dir: self,
+            snapshot: None,
+        }
+    }
+}
+
+/// decides which files the text processing methods of `Dir` treat as
+/// text, e.g. `Dir::normalize_line_endings_with`
+///
+/// Any `Fn(&Path, &File) -> bool` is a classifier, so a closure can force
+/// files to be text or binary by path or extension.
+pub trait TextClassifier {
+    /// whether the file at `path` (relative to the root) is text
+    fn is_text(&self, path: &Path, file: &File) -> bool;
+}
+
+impl<F: Fn(&Path, &File) -> bool> TextClassifier for F {
+    fn is_text(&self, path: &Path, file: &File) -> bool {
+        self(path, file)
+    }
+}
+
+/// the default classifier, using `File::is_likely_text`
+struct LikelyText;
+
+impl TextClassifier for LikelyText {
+    fn is_text(&self, _: &Path, file: &File) -> bool {
+        file.is_likely_text()
+    }
+}
+
+/// iterator over the relative path of every entry in a tree, see
+/// `Dir::paths`
+pub struct Paths<'a> {
+    stack: Vec<(PathBuf, hash_map::Iter<'a, OsString, Entry>)>,
+}
+
+impl<'a> Iterator for Paths<'a> {
+    type Item = PathBuf;
+
+    fn next(&mut self) -> Option<PathBuf> {
+        loop {
+            let (path, subdir) = {
+                let &mut (ref prefix, ref mut iter) = self.stack.last_mut()?;
+                match iter.next() {
+                    Some((name, entry)) => {
+                        let subdir = match *entry {
+                            Entry::Dir(ref d) => Some(d.items.iter()),
+                            Entry::File(_) => None,
+                        };
+                        (prefix.join(name), subdir)
+                    }
+                    None => {
+                        self.stack.pop();
+                        continue;
+                    }
+                }
+            };
+            if let Some(iter) = subdir {
+                self.stack.push((path.clone(), iter));
+            }
+            return Some(path);
+        }
+    }
+}
+
+/// lazy iterator over the differences between two trees, see
+/// `Dir::diff_stream`
+pub struct DiffStream<'a> {
+    stack: Vec<DiffFrame<'a>>,
+    /// the `Added` half of an entry which changed type
+    pending: Option<Diff>,
+}
+
+/// two directories at the same path being compared
+struct DiffFrame<'a> {
+    prefix: PathBuf,
+    left: &'a Dir,
+    right: &'a Dir,
+    names: vec::IntoIter<&'a OsString>,
+}
+
+impl<'a> DiffFrame<'a> {
+    fn new(prefix: PathBuf, left: &'a Dir, right: &'a Dir) -> DiffFrame<'a> {
+        let names: BTreeSet<&OsString> = left.items.keys().chain(right.items.keys()).collect();
+        DiffFrame {
+            prefix,
+            left,
+            right,
+            names: names.into_iter().collect::<Vec<_>>().into_iter(),
+        }
+    }
+}
+
+impl<'a> Iterator for DiffStream<'a> {
+    type Item = Diff;
+
+    fn next(&mut self) -> Option<Diff> {
+        if let Some(diff) = self.pending.take() {
+            return Some(diff);
+        }
+        loop {
+            let subdirs = {
+                let frame = self.stack.last_mut()?;
+                let name = match frame.names.next() {
+                    Some(n) => n,
+                    None => {
+                        self.stack.pop();
+                        continue;
+                    }
+                };
+                let path = frame.prefix.join(name);
+                match (frame.left.items.get(name), frame.right.items.get(name)) {
+                    (Some(Entry::File(a)), Some(Entry::File(b))) => {
+                        if a != b {
+                            return Some(Diff::ModifiedFile(path));
+                        }
+                        continue;
+                    }
+                    (Some(Entry::Dir(a)), Some(Entry::Dir(b))) => DiffFrame::new(path, a, b),
+                    (Some(_), Some(_)) => {
+                        self.pending = Some(Diff::Added(path.clone()));
+                        return Some(Diff::Removed(path));
+                    }
+                    (Some(_), None) => return Some(Diff::Removed(path)),
+                    (None, Some(_)) => return Some(Diff::Added(path)),
+                    (None, None) => unreachable!(),
+                }
+            };
+            self.stack.push(subdirs);
+        }
+    }
+}
+
+/// a set of edits to a `Dir` which are either all kept or all undone
+///
+/// The transaction dereferences to the `Dir` being edited. A snapshot is
+/// only taken the first time it is borrowed mutably, so rolling back a
+/// transaction which changed nothing is free. Dropping the transaction
+/// without calling `commit` rolls it back.
+pub struct Transaction<'a> {
+    dir: &'a mut Dir,
+    snapshot: Option<Dir>,
+}
+
+impl<'a> Transaction<'a> {
+    /// keep all edits made during the transaction
+    pub fn commit(mut self) {
+        self.snapshot = None;
+    }
+
+    /// restore the `Dir` to its state from before the transaction
+    pub fn rollback(self) {
+        // restoring is done on drop
+    }
+}
+
+impl<'a> Deref for Transaction<'a> {
+    type Target = Dir;
+
+    fn deref(&self) -> &Dir {
+        self.dir
+    }
+}
+
+impl<'a> DerefMut for Transaction<'a> {
+    fn deref_mut(&mut self) -> &mut Dir {
+        if self.snapshot.is_none() {
+            self.snapshot = Some(self.dir.clone_shared());
+        }
+        self.dir
+    }
+}
+
+impl<'a> Drop for Transaction<'a> {
+    fn drop(&mut self) {
+        if let Some(snapshot) = self.snapshot.take() {
+            *self.dir = snapshot;
+        }
+    }
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// makes the temporary names used by `File::dump_atomic` unique within
+/// the process
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// lines of context around each hunk of a unified diff
+const DIFF_CONTEXT: usize = 3;
+
+enum LineOp<'a> {
+    Equal(&'a str),
+    Delete(&'a str),
+    Insert(&'a str),
+}
+
+/// a line diff based on the longest common subsequence
+///
+/// Lines shared at the start and end are matched directly, but the lines
+/// between them take quadratic time and memory.
+fn diff_lines<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<LineOp<'a>> {
+    let prefix = old.iter().zip(new).take_while(|&(a, b)| a == b).count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev())
+        .take_while(|&(a, b)| a == b)
+        .count();
+    let (old_mid, new_mid) = (&old[prefix..old.len() - suffix], &new[prefix..new.len() - suffix]);
+
+    let mut ops: Vec<LineOp> = old[..prefix].iter().map(|&l| LineOp::Equal(l)).collect();
+    diff_lines_lcs(old_mid, new_mid, &mut ops);
+    ops.extend(old[old.len() - suffix..].iter().map(|&l| LineOp::Equal(l)));
+    ops
+}
+
+fn diff_lines_lcs<'a>(old: &[&'a str], new: &[&'a str], ops: &mut Vec<LineOp<'a>>) {
+    // lcs[i][j] is the LCS length of old[i..] and new[j..]
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            ops.push(LineOp::Equal(old[i]));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            ops.push(LineOp::Delete(old[i]));
+            i += 1;
+        } else {
+            ops.push(LineOp::Insert(new[j]));
+            j += 1;
+        }
+    }
+}
+
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// a minimal SHA-256, so that manifests can be checked by standard tools
+/// without pulling in a dependency
+fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut h: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a,
+        0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+    ];
+    let mut msg = data.to_vec();
+    let bit_len = (data.len() as u64).wrapping_mul(8);
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    msg.extend_from_slice(&bit_len.to_be_bytes());
+
+    for chunk in msg.chunks(64) {
+        let mut w = [0u32; 64];
+        for (i, word) in chunk.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+        let mut v = h;
+        for i in 0..64 {
+            let s1 = v[4].rotate_right(6) ^ v[4].rotate_right(11) ^ v[4].rotate_right(25);
+            let ch = (v[4] & v[5]) ^ (!v[4] & v[6]);
+            let t1 = v[7].wrapping_add(s1).wrapping_add(ch)
+                .wrapping_add(SHA256_K[i]).wrapping_add(w[i]);
+            let s0 = v[0].rotate_right(2) ^ v[0].rotate_right(13) ^ v[0].rotate_right(22);
+            let maj = (v[0] & v[1]) ^ (v[0] & v[2]) ^ (v[1] & v[2]);
+            let t2 = s0.wrapping_add(maj);
+            v = [t1.wrapping_add(t2), v[0], v[1], v[2], v[3].wrapping_add(t1), v[4], v[5], v[6]];
+        }
+        for (hi, vi) in h.iter_mut().zip(v.iter()) {
+            *hi = hi.wrapping_add(*vi);
+        }
+    }
+
+    let mut out = [0u8; 32];
+    for (i, word) in h.iter().enumerate() {
+        out[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::str;
+    use std::path::PathBuf;
+    use std::thread;
+
+    fn validate_dir(level: usize, dir: &Dir) -> Result<(), String> {
+        for (n, entry) in dir.entries() {
+            let name = n.to_str().unwrap();
+            let (name_prefix, last_c) = name.split_at(name.len()-1);
+            last_c.parse::<usize>().unwrap_or_else(
+                |_| panic!("last_c: {}, invalid name: {}", last_c, name));
+            match *entry {
+                Entry::File(ref file) => {
+                    if name_prefix != format!("file{}-", level) {
+                        return Err(format!("lvl {} file {} has invalid name", level, name));
+                    }
+                    let data = str::from_utf8(file.bytes()).unwrap();
+                    if data.trim() != name {
+                        return Err(format!("lvl {} file {} has invalid data: \"{}\"", 
+                                           level, name, data));
+                    }
+                }
+                Entry::Dir(ref dir) => {
+                    if name_prefix != format!("dir{}-", level) {
+                        return Err(format!("invalid dir name at level {}: {}", level, name));
+                    }
+                    //println!("validating dir name: {}", name);
+                    validate_dir(level + 1, dir)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn count_entries(dir: &Dir) -> usize {
+        let mut count = 0;
+        for (_, entry) in dir.entries() {
+            count += 1;
+            if let Entry::Dir(ref d) = *entry {
+                count += count_entries(d);
+            }
+        }
+        count
+    }
+
+    #[test]
+    /// really basic test to just load a directory, validate it, 
+    /// save it to a new folder and reload + validate it
+    fn test() {
+        let cwd = env::current_dir().unwrap();
+        let test_dir: PathBuf = cwd.join(PathBuf::from(
+            file!()).parent().unwrap());
+        let data_dir: PathBuf = test_dir.join(PathBuf::from("data"));
+
+        println!("loading data dir: {}", data_dir.display());
+        let dir = Dir::load(&data_dir).unwrap();
+        println!("validating data dir: {}", data_dir.display());
+        assert_eq!(count_entries(&dir), 10);
+        validate_dir(0, &dir).expect("data dir");
+        let tmp: PathBuf = test_dir.join(PathBuf::from("test_out_dir"));
+
+        println!("dumping to: {}", tmp.display());
+        dir.dump(&tmp).expect("couldn't dump dir");
+        let result = Dir::load(&tmp).unwrap();
+        assert_eq!(result, dir);
+        fs::remove_dir_all(tmp).expect("couldn't remove");
+    }
+
+    fn data_dir() -> PathBuf {
+        let cwd = env::current_dir().unwrap();
+        cwd.join(PathBuf::from(file!()).parent().unwrap()).join("data")
+    }
+
+    fn load_data() -> Dir {
+        Dir::load(&data_dir()).unwrap()
+    }
+
+    fn file_at<'a>(dir: &'a Dir, path: &str) -> &'a File {
+        match dir.get(Path::new(path)) {
+            Some(Entry::File(f)) => f,
+            e => panic!("{} is not a file: {:?}", path, e),
+        }
+    }
+
+    fn dir_at<'a>(dir: &'a Dir, path: &str) -> &'a Dir {
+        match dir.get(Path::new(path)) {
+            Some(Entry::Dir(d)) => d,
+            e => panic!("{} is not a dir: {:?}", path, e),
+        }
+    }
+
+    /// a fresh, not yet existing output path for a test
+    fn out_dir(name: &str) -> PathBuf {
+        let cwd = env::current_dir().unwrap();
+        let path = cwd.join(PathBuf::from(file!()).parent().unwrap()).join(name);
+        let _ = fs::remove_dir_all(&path);
+        path
+    }
+
+    #[test]
+    fn test_dump_atomic() {
+        let tmp = out_dir("test_out_atomic");
+        fs::create_dir(&tmp).unwrap();
+        let path = tmp.join("out.txt");
+        File::new(b"first".to_vec()).dump(&path).unwrap();
+
+        File::new(b"second".to_vec()).dump_atomic(&path).unwrap();
+        assert_eq!(File::load(&path).unwrap().bytes(), b"second");
+        let names: Vec<_> = fs::read_dir(&tmp).unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(names, vec![OsString::from("out.txt")]);
+
+        // a file which looks like a leftover temporary is not touched
+        let next = TMP_COUNTER.load(Ordering::Relaxed);
+        let stale = tmp.join(format!(".out.txt.tmp-{}-{}", process::id(), next));
+        File::new(b"stale".to_vec()).dump(&stale).unwrap();
+        let threads: Vec<_> = (0..8).map(|i| {
+            let path = path.clone();
+            thread::spawn(move || File::new(vec![i; 10_000]).dump_atomic(&path).unwrap())
+        }).collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        let written = File::load(&path).unwrap();
+        assert_eq!(written.bytes().len(), 10_000);
+        assert!(written.bytes().iter().all(|&b| b == written.bytes()[0]));
+        assert_eq!(File::load(&stale).unwrap().bytes(), b"stale");
+        assert_eq!(fs::read_dir(&tmp).unwrap().count(), 2);
+        fs::remove_dir_all(tmp).unwrap();
+    }
+
+    #[test]
+    fn test_object_count() {
+        let dir = load_data();
+        let tmp = out_dir("test_out_object_count");
+        dir.dump(&tmp).unwrap();
+        let mut on_disk = 1;
+        let mut stack = vec![tmp.clone()];
+        while let Some(p) = stack.pop() {
+            for e in fs::read_dir(p).unwrap() {
+                let e = e.unwrap();
+                on_disk += 1;
+                if e.file_type().unwrap().is_dir() {
+                    stack.push(e.path());
+                }
+            }
+        }
+        assert_eq!(dir.object_count(), on_disk);
+        assert_eq!(dir.object_count(), count_entries(&dir) + 1);
+        assert_eq!(Dir::new().object_count(), 1);
+        fs::remove_dir_all(tmp).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "regex")]
+    fn test_replace_regex() {
+        let mut dir = Dir::new();
+        let mut sub = Dir::new();
+        dir.add_file(OsString::from("a"), File::new(b"use v1.2 and v3.4".to_vec())).unwrap();
+        sub.add_file(OsString::from("b"), File::new(b"v10.0\n".to_vec())).unwrap();
+        sub.add_file(OsString::from("bin"), File::new(b"v1.2\0".to_vec())).unwrap();
+        dir.add_dir(OsString::from("sub"), sub).unwrap();
+
+        assert_eq!(dir.replace_regex(r"v(\d+)\.(\d+)", "version $1-$2").unwrap(), 3);
+        assert_eq!(file_at(&dir, "a").bytes(), b"use version 1-2 and version 3-4");
+        assert_eq!(file_at(&dir, "sub/b").bytes(), b"version 10-0\n");
+        assert_eq!(file_at(&dir, "sub/bin").bytes(), b"v1.2\0");
+        assert!(dir.replace_regex("(", "").is_err());
+
+        let only_bin = |path: &Path, _: &File| path.ends_with("bin");
+        assert_eq!(dir.replace_regex_with(r"v(\d+)", "V$1", only_bin).unwrap(), 1);
+        assert_eq!(file_at(&dir, "sub/bin").bytes(), b"V1.2\0");
+    }
+
+    #[test]
+    fn test_normalize_line_endings() {
+        let mut dir = Dir::new();
+        dir.add_file(OsString::from("a.txt"), File::new(b"one\r\ntwo\r\n".to_vec())).unwrap();
+        dir.add_file(OsString::from("b.txt"), File::new(b"lone\r cr\n".to_vec())).unwrap();
+        dir.add_file(OsString::from("data.bin"), File::new(b"\0x\r\ny\r\n".to_vec())).unwrap();
+
+        let mut default = dir.clone();
+        assert_eq!(default.normalize_line_endings(), 1);
+        assert_eq!(file_at(&default, "a.txt").bytes(), b"one\ntwo\n");
+        assert_eq!(file_at(&default, "b.txt").bytes(), b"lone\r cr\n");
+        assert_eq!(file_at(&default, "data.bin").bytes(), b"\0x\r\ny\r\n");
+
+        let forced = |path: &Path, file: &File| {
+            path.extension() == Some(OsStr::new("bin")) || file.is_likely_text()
+        };
+        assert_eq!(dir.normalize_line_endings_with(forced), 2);
+        assert_eq!(file_at(&dir, "data.bin").bytes(), b"\0x\ny\n");
+    }
+
+    #[test]
+    fn test_ensure_trailing_newline() {
+        let mut dir = Dir::new();
+        dir.add_file(OsString::from("missing"), File::new(b"no newline".to_vec())).unwrap();
+        dir.add_file(OsString::from("present"), File::new(b"newline\n".to_vec())).unwrap();
+        dir.add_file(OsString::from("empty"), File::new(vec![])).unwrap();
+        dir.add_file(OsString::from("data.bin"), File::new(b"\0bin".to_vec())).unwrap();
+
+        assert_eq!(dir.ensure_trailing_newline(), 1);
+        assert_eq!(file_at(&dir, "missing").bytes(), b"no newline\n");
+        assert_eq!(file_at(&dir, "present").bytes(), b"newline\n");
+        assert_eq!(file_at(&dir, "empty").bytes(), b"");
+        assert_eq!(file_at(&dir, "data.bin").bytes(), b"\0bin");
+        assert_eq!(dir.ensure_trailing_newline(), 0);
+
+        let only_bin = |path: &Path, _: &File| path.ends_with("data.bin");
+        assert_eq!(dir.ensure_trailing_newline_with(only_bin), 1);
+        assert_eq!(file_at(&dir, "data.bin").bytes(), b"\0bin\n");
+    }
+
+    #[test]
+    fn test_strip_bom() {
+        let mut file = File::new(b"\xef\xbb\xbftext".to_vec());
+        assert!(file.strip_bom());
+        assert_eq!(file.bytes(), b"text");
+        assert!(!file.strip_bom());
+        assert_eq!(file.bytes(), b"text");
 
-        println!("dumping to: {}", tmp.display());
-        dir.dump(&tmp).expect("couldn't dump dir");
-        let result = Dir::load(&tmp).unwrap();
-        assert_eq!(result, dir);
-        fs::remove_dir_all(tmp).expect("couldn't remove");
+        let mut utf16 = File::new(b"\xff\xfeh\x00i\x00".to_vec());
+        assert!(utf16.strip_bom());
+        assert_eq!(utf16.bytes(), b"h\x00i\x00");
+        assert!(!File::new(vec![]).strip_bom());
+        let mut utf32 = File::new(b"\xff\xfe\x00\x00h\x00\x00\x00".to_vec());
+        assert!(!utf32.strip_bom());
+        assert_eq!(utf32.bytes(), b"\xff\xfe\x00\x00h\x00\x00\x00");
+
+        let mut dir = load_data();
+        dir.add_file(OsString::from("bom"), File::new(b"\xef\xbb\xbfx".to_vec())).unwrap();
+        dir.add_file(OsString::from("utf16"), File::new(b"\xff\xfeh\x00i\x00".to_vec())).unwrap();
+        dir.add_file(OsString::from("utf32"), File::new(b"\xff\xfe\x00\x00h\x00\x00\x00".to_vec()))
+            .unwrap();
+        dir.add_file(OsString::from("bin"), File::new(b"\xef\xbb\xbf\x00".to_vec())).unwrap();
+        assert_eq!(dir.strip_boms(), 2);
+        assert_eq!(file_at(&dir, "bom").bytes(), b"x");
+        assert_eq!(file_at(&dir, "utf16").bytes(), b"h\x00i\x00");
+        assert_eq!(file_at(&dir, "utf32").bytes(), b"\xff\xfe\x00\x00h\x00\x00\x00");
+        assert_eq!(file_at(&dir, "bin").bytes(), b"\xef\xbb\xbf\x00");
+        let mut forced = dir.clone();
+        assert_eq!(forced.strip_boms_with(|path: &Path, _: &File| path.ends_with("bin")), 1);
+        assert_eq!(file_at(&forced, "bin").bytes(), b"\x00");
+        assert_eq!(dir.strip_boms(), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "regex")]
+    fn test_rename_paths_regex() {
+        let mut dir = Dir::new();
+        dir.load_into(Path::new("src/foo"), &data_dir().join("dir0-1"), MergePolicy::Error).unwrap();
+        dir.dir_mut_p(Path::new("src/foo")).unwrap()
+            .add_file(OsString::from("mod.rs"), File::new(b"foo".to_vec())).unwrap();
+        dir.dir_mut_p(Path::new("src/bar/baz")).unwrap()
+            .add_file(OsString::from("mod.rs"), File::new(b"baz".to_vec())).unwrap();
+        dir.add_file(OsString::from("mod.rs"), File::new(b"top".to_vec())).unwrap();
+
+        assert_eq!(dir.rename_paths_regex(r"^src/(.*)/mod\.rs$", "src/$1.rs").unwrap(), 2);
+        assert_eq!(file_at(&dir, "src/foo.rs").bytes(), b"foo");
+        assert_eq!(file_at(&dir, "src/bar/baz.rs").bytes(), b"baz");
+        assert_eq!(file_at(&dir, "src/foo/file1-0").bytes(), b"file1-0\n");
+        assert_eq!(file_at(&dir, "mod.rs").bytes(), b"top");
+        assert_eq!(dir.get(Path::new("src/foo/mod.rs")), None);
+        assert!(dir_at(&dir, "src/bar/baz").is_empty());
+
+        let before = dir.clone();
+        let err = dir.rename_paths_regex(r"^src/.*\.rs$", "all.rs").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert!(err.to_string().contains("src/bar/baz.rs"), "{}", err);
+        assert!(err.to_string().contains("src/foo.rs"), "{}", err);
+        let err = dir.rename_paths_regex(r"^src/foo\.rs$", "mod.rs").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(dir, before);
+
+        // a1 -> a and a2/b -> a/b can't both happen
+        let mut dir = Dir::new();
+        dir.add_file(OsString::from("a1"), File::new(vec![])).unwrap();
+        dir.dir_mut_p(Path::new("a2")).unwrap()
+            .add_file(OsString::from("b"), File::new(vec![])).unwrap();
+        let before = dir.clone();
+        let err = dir.rename_paths_regex(r"\d", "").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(dir, before);
+    }
+
+    #[test]
+    fn test_clone_shared() {
+        let dir = load_data();
+        let mut shared = dir.clone_shared();
+        assert_eq!(shared, dir);
+
+        let name = OsStr::new("file0-0");
+        let orig = match dir.items[name] {
+            Entry::File(ref f) => f,
+            _ => panic!("not a file"),
+        };
+        let copy = match *shared.items.get_mut(name).unwrap() {
+            Entry::File(ref mut f) => f,
+            _ => panic!("not a file"),
+        };
+        assert!(Arc::ptr_eq(&orig.bytes, &copy.bytes));
+        assert!(!Arc::ptr_eq(&orig.bytes, &orig.clone().bytes));
+
+        copy.bytes_mut().extend_from_slice(b"changed");
+        assert!(!Arc::ptr_eq(&orig.bytes, &copy.bytes));
+        assert_eq!(orig.bytes(), b"file0-0\n");
+        assert_eq!(copy.bytes(), b"file0-0\nchanged");
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn test_sha256() {
+        assert_eq!(hex(&sha256(b"")),
+                   "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(hex(&sha256(b"abc")),
+                   "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(hex(&sha256(&[b'a'; 1000])),
+                   "41edece42d63e8d9bf515a9ba6932e1c20cbc9f5a5d134645adb5db1b9737ea3");
+    }
+
+    #[test]
+    fn test_dump_with_manifest() {
+        let dir = load_data();
+        let tmp = out_dir("test_out_manifest");
+        dir.dump_with_manifest(&tmp, "SHA256SUMS").unwrap();
+
+        let manifest = fs::read_to_string(tmp.join("SHA256SUMS")).unwrap();
+        let lines: Vec<_> = manifest.lines().collect();
+        assert_eq!(lines.len(), 7);
+        assert!(lines.iter().any(|l| l.ends_with("  dir0-0/dir1-0/file2-0")));
+        for line in lines {
+            let (digest, path) = line.split_at(64);
+            assert!(path.starts_with("  "));
+            assert_ne!(&path[2..], "SHA256SUMS");
+            let file = File::load(&tmp.join(&path[2..])).unwrap();
+            assert_eq!(hex(&file.sha256()), digest);
+        }
+        fs::remove_dir_all(&tmp).unwrap();
+
+        for name in &["", "..", "sub/SHA256SUMS", "/SHA256SUMS"] {
+            let err = dir.dump_with_manifest(&tmp, name).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+            assert!(!tmp.exists());
+        }
+        let err = dir.dump_with_manifest(&tmp, "file0-0").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert!(!tmp.exists());
+
+        let mut escaped = Dir::new();
+        escaped.add_file(OsString::from("a\\b"), File::new(vec![])).unwrap();
+        escaped.add_file(OsString::from("c\nd"), File::new(vec![])).unwrap();
+        escaped.dump_with_manifest(&tmp, "SHA256SUMS").unwrap();
+        let empty = hex(&File::new(vec![]).sha256());
+        assert_eq!(fs::read_to_string(tmp.join("SHA256SUMS")).unwrap(),
+                   format!("\\{0}  a\\\\b\n\\{0}  c\\nd\n", empty));
+        fs::remove_dir_all(&tmp).unwrap();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStringExt;
+            let mut lossy = Dir::new();
+            lossy.add_file(OsString::from_vec(b"\xff".to_vec()), File::new(vec![])).unwrap();
+            let err = lossy.dump_with_manifest(&tmp, "SHA256SUMS").unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(!tmp.exists());
+        }
+    }
+
+    #[test]
+    fn test_len() {
+        let dir = load_data();
+        assert_eq!(dir.len(), 5);
+        assert_eq!(dir.len(), dir.entries().count());
+        assert!(!dir.is_empty());
+        assert_eq!(dir_at(&dir, "dir0-1").len(), 1);
+        assert!(Dir::new().is_empty());
+    }
+
+    #[test]
+    fn test_get_all_ci() {
+        let mut dir = Dir::new();
+        let mut sub = Dir::new();
+        sub.add_file(OsString::from("File.TXT"), File::new(vec![])).unwrap();
+        sub.add_file(OsString::from("file.txt"), File::new(vec![])).unwrap();
+        sub.add_file(OsString::from("other.txt"), File::new(vec![])).unwrap();
+        dir.add_dir(OsString::from("Sub"), sub).unwrap();
+
+        assert_eq!(dir.get_all_ci(Path::new("sub/file.txt")),
+                   vec![PathBuf::from("Sub/File.TXT"), PathBuf::from("Sub/file.txt")]);
+        assert_eq!(dir.get_all_ci(Path::new("SUB/OTHER.txt")),
+                   vec![PathBuf::from("Sub/other.txt")]);
+        assert!(dir.get_all_ci(Path::new("sub/missing")).is_empty());
+        assert!(dir.get_all_ci(Path::new("sub/file.txt/x")).is_empty());
+    }
+
+    #[test]
+    fn test_get() {
+        let dir = load_data();
+        assert_eq!(dir.get(Path::new("dir0-0/dir1-0/file2-0")), Some(&Entry::file("file2-0\n")));
+        assert_eq!(dir.get(Path::new("./file0-1")), Some(&Entry::file("file0-1\n")));
+        assert!(matches!(dir.get(Path::new("dir0-1")), Some(&Entry::Dir(_))));
+        assert_eq!(file_at(dir_at(&dir, "dir0-0"), "dir1-0/file2-0").bytes(), b"file2-0\n");
+        assert_eq!(dir.get(Path::new("file0-1/nope")), None);
+        assert_eq!(dir.get(Path::new("../file0-1")), None);
+        assert_eq!(dir.get(Path::new("")), None);
+    }
+
+    #[test]
+    fn test_under_prefix() {
+        let dir = load_data();
+        let wrapped = dir.clone().under_prefix(Path::new("pkg/v1")).unwrap();
+        assert_eq!(wrapped.entries().count(), 1);
+        assert_eq!(wrapped.get(Path::new("pkg/v1/dir0-0/file1-1")),
+                   dir.get(Path::new("dir0-0/file1-1")));
+        assert_eq!(*dir_at(&wrapped, "pkg/v1"), dir);
+        assert_eq!(dir.clone().under_prefix(Path::new("")).unwrap(), dir);
+        assert_eq!(dir.clone().under_prefix(Path::new("/pkg/./v1")).unwrap(), wrapped);
+        for bad in &["..", "../evil/..", "pkg/../v1"] {
+            let err = dir.clone().under_prefix(Path::new(bad)).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn test_unified_diff() {
+        let old = File::new(b"1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n13\n14\n15\n".to_vec());
+        let new = File::new(b"1\n2\nthree\n4\n5\n6\n7\n8\n9\n10\n11\n12\n14\n15\n16\n".to_vec());
+        let expected = "\
+--- a/x.txt
++++ b/x.txt
+@@ -1,6 +1,6 @@
+ 1
+ 2
+-3
++three
+ 4
+ 5
+ 6
+@@ -10,6 +10,6 @@
+ 10
+ 11
+ 12
+-13
+ 14
+ 15
++16
+";
+        assert_eq!(old.unified_diff(&new, "x.txt").unwrap(), expected);
+        // changes separated by exactly twice the context share a hunk
+        let close = File::new(b"1\n2\nthree\n4\n5\n6\n7\n8\n9\nten\n11\n12\n13\n14\n15\n".to_vec());
+        assert_eq!(old.unified_diff(&close, "x.txt").unwrap().matches("@@ -").count(), 1);
+        assert_eq!(old.unified_diff(&old.clone(), "x.txt"), None);
+        assert_eq!(old.unified_diff(&File::new(vec![0xff]), "x.txt"), None);
+
+        // a small edit to a large file doesn't build a huge table
+        let mut lines: Vec<String> = (0..50_000).map(|i| format!("{}\n", i)).collect();
+        let big = File::new(lines.concat().into_bytes());
+        lines.insert(20_000, "inserted\n".to_string());
+        let diff = big.unified_diff(&File::new(lines.concat().into_bytes()), "big").unwrap();
+        assert_eq!(diff, concat!("--- a/big\n+++ b/big\n@@ -19998,6 +19998,7 @@\n",
+                                 " 19997\n 19998\n 19999\n+inserted\n 20000\n 20001\n 20002\n"));
+
+        let empty = File::new(vec![]);
+        assert_eq!(empty.unified_diff(&File::new(b"x\n".to_vec()), "e").unwrap(),
+                   "--- a/e\n+++ b/e\n@@ -0,0 +1,1 @@\n+x\n");
+
+        let newline = File::new(b"a\n".to_vec());
+        assert_eq!(newline.unified_diff(&File::new(b"a".to_vec()), "x").unwrap(),
+                   "--- a/x\n+++ b/x\n@@ -1,1 +1,1 @@\n-a\n+a\n\\ No newline at end of file\n");
+        let crlf = File::new(b"a\r\nb\r\n".to_vec());
+        assert_eq!(crlf.unified_diff(&File::new(b"a\nb\n".to_vec()), "x").unwrap(),
+                   "--- a/x\n+++ b/x\n@@ -1,2 +1,2 @@\n-a\r\n-b\r\n+a\n+b\n");
+    }
+
+    #[test]
+    fn test_dump_resume() {
+        let dir = load_data();
+        let tmp = out_dir("test_out_resume");
+        fs::create_dir(&tmp).unwrap();
+        fs::create_dir(tmp.join("dir0-0")).unwrap();
+        File::new(b"file0-0\n".to_vec()).dump(&tmp.join("file0-0")).unwrap();
+        // right length, wrong contents
+        File::new(b"FILE1-0\n".to_vec()).dump(&tmp.join("dir0-0/file1-0")).unwrap();
+        // wrong length
+        File::new(b"partial".to_vec()).dump(&tmp.join("file0-1")).unwrap();
+
+        let stats = dir.dump_resume(&tmp).unwrap();
+        assert_eq!(stats, DumpStats { written: 5, skipped: 2 });
+        assert_eq!(File::load(&tmp.join("file0-1")).unwrap().bytes(), b"file0-1\n");
+        assert_eq!(File::load(&tmp.join("dir0-0/file1-0")).unwrap().bytes(), b"FILE1-0\n");
+
+        let stats = dir.dump_resume_verified(&tmp).unwrap();
+        assert_eq!(stats, DumpStats { written: 1, skipped: 6 });
+        assert_eq!(Dir::load(&tmp).unwrap(), dir);
+        fs::remove_dir_all(tmp).unwrap();
+    }
+
+    #[test]
+    fn test_dirs_exceeding() {
+        let mut dir = load_data();
+        let mut big = Dir::new();
+        for i in 0..20 {
+            big.add_file(OsString::from(format!("f{}", i)), File::new(vec![])).unwrap();
+        }
+        dir.add_dir(OsString::from("big"), big).unwrap();
+
+        assert_eq!(dir.dirs_exceeding(10), vec![(PathBuf::from("big"), 20)]);
+        assert_eq!(dir.dirs_exceeding(5),
+                   vec![(PathBuf::new(), 6), (PathBuf::from("big"), 20)]);
+        assert!(dir.dirs_exceeding(20).is_empty());
+    }
+
+    #[test]
+    fn test_merge3() {
+        let base = load_data();
+        let mut ours = base.clone();
+        let mut theirs = base.clone();
+        ours.items.insert(OsString::from("file0-0"), Entry::file("ours"));
+        ours.add_file(OsString::from("same"), File::new(b"same".to_vec())).unwrap();
+        match *theirs.items.get_mut(OsStr::new("dir0-0")).unwrap() {
+            Entry::Dir(ref mut d) => d.items.insert(OsString::from("file1-0"), Entry::file("theirs")),
+            _ => panic!("not a dir"),
+        };
+        theirs.items.remove(OsStr::new("file0-2"));
+        theirs.add_file(OsString::from("same"), File::new(b"same".to_vec())).unwrap();
+
+        let (merged, conflicts) = Dir::merge3(&base, &ours, &theirs);
+        assert!(conflicts.is_empty());
+        assert_eq!(file_at(&merged, "file0-0").bytes(), b"ours");
+        assert_eq!(file_at(&merged, "dir0-0/file1-0").bytes(), b"theirs");
+        assert_eq!(file_at(&merged, "same").bytes(), b"same");
+        assert_eq!(merged.get(Path::new("file0-2")), None);
+        assert_eq!(merged.get(Path::new("dir0-1")), base.get(Path::new("dir0-1")));
+        assert!(Arc::ptr_eq(&file_at(&merged, "dir0-1/file1-0").as_arc(),
+                            &file_at(&ours, "dir0-1/file1-0").as_arc()));
+        assert!(Arc::ptr_eq(&file_at(&merged, "dir0-0/file1-0").as_arc(),
+                            &file_at(&theirs, "dir0-0/file1-0").as_arc()));
+
+        match *theirs.items.get_mut(OsStr::new("dir0-0")).unwrap() {
+            Entry::Dir(ref mut d) => d.items.insert(OsString::from("file1-0"), Entry::file("x")),
+            _ => panic!("not a dir"),
+        };
+        match *ours.items.get_mut(OsStr::new("dir0-0")).unwrap() {
+            Entry::Dir(ref mut d) => d.items.insert(OsString::from("file1-0"), Entry::file("y")),
+            _ => panic!("not a dir"),
+        };
+        let (merged, conflicts) = Dir::merge3(&base, &ours, &theirs);
+        assert_eq!(conflicts, vec![Conflict { path: PathBuf::from("dir0-0/file1-0") }]);
+        assert_eq!(file_at(&merged, "dir0-0/file1-0").bytes(), b"y");
+    }
+
+    /// deterministic pseudo-random bytes (xorshift)
+    fn random_bytes(len: usize) -> Vec<u8> {
+        let mut state: u32 = 0x2545_f491;
+        (0..len).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            (state >> 24) as u8
+        }).collect()
+    }
+
+    #[test]
+    #[cfg(feature = "flate2")]
+    fn test_maybe_compress() {
+        let text = File::new(b"compress me ".repeat(1000));
+        let stored = text.maybe_compress();
+        match stored {
+            StoredBytes::Compressed(ref c) => assert!(c.len() < text.bytes().len()),
+            ref s => panic!("not compressed: {:?}", s),
+        }
+        assert_eq!(stored.to_file().unwrap(), text);
+
+        let random = File::new(random_bytes(10_000));
+        let stored = random.maybe_compress();
+        match stored {
+            StoredBytes::Raw(ref r) => assert!(Arc::ptr_eq(r, &random.as_arc())),
+            ref s => panic!("not raw: {:?}", s),
+        }
+        assert_eq!(stored.to_file().unwrap(), random);
+
+        // too short to benefit
+        assert!(matches!(File::new(b"ab".to_vec()).maybe_compress(), StoredBytes::Raw(_)));
+    }
+
+    #[test]
+    fn test_base64() {
+        let cases: &[(&[u8], &str)] = &[
+            (b"", ""),
+            (b"f", "Zg=="),
+            (b"fo", "Zm8="),
+            (b"foo", "Zm9v"),
+            (b"foob", "Zm9vYg=="),
+            (b"fooba", "Zm9vYmE="),
+            (b"foobar", "Zm9vYmFy"),
+            (b"\xff\xfe\x00", "//4A"),
+        ];
+        for &(raw, encoded) in cases {
+            let file = File::new(raw.to_vec());
+            assert_eq!(file.to_base64(), encoded);
+            assert_eq!(File::from_base64(encoded).unwrap(), file);
+        }
+        let random = File::new(random_bytes(1000));
+        assert_eq!(File::from_base64(&random.to_base64()).unwrap(), random);
+
+        for bad in &["Zg", "Zg=a", "Z===", "Zg==Zm9v", "Zm9*"] {
+            let err = File::from_base64(bad).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_find_all() {
+        let file = File::new(b"one\0two\0three\0".to_vec());
+        assert_eq!(file.find_all(b"\0"), vec![3, 7, 13]);
+        assert_eq!(file.find_all(b"t"), vec![4, 8]);
+        assert_eq!(file.find_all(b"four"), Vec::<usize>::new());
+        assert_eq!(file.find_all(b"three\0more"), Vec::<usize>::new());
+        assert_eq!(file.find_all(b""), Vec::<usize>::new());
+
+        let file = File::new(b"aaaaa".to_vec());
+        assert_eq!(file.find_all(b"aa"), vec![0, 2]);
+        assert_eq!(File::new(b"abab".to_vec()).find_all(b"aba"), vec![0]);
+    }
+
+    #[test]
+    fn test_entropy() {
+        let constant = File::new(vec![b'a'; 4096]);
+        assert_eq!(constant.byte_histogram()[b'a' as usize], 4096);
+        assert!(constant.entropy().abs() < 1e-9);
+        assert_eq!(File::new(vec![]).entropy(), 0.0);
+        assert!((File::new(vec![0, 1]).entropy() - 1.0).abs() < 1e-9);
+
+        let random = File::new(random_bytes(1 << 16));
+        assert_eq!(random.byte_histogram().iter().sum::<u64>(), 1 << 16);
+        assert!(random.entropy() > 7.9, "entropy: {}", random.entropy());
+    }
+
+    #[test]
+    fn test_remove_files_where() {
+        let mut dir = load_data();
+        let mut sub = Dir::new();
+        sub.add_file(OsString::from("empty"), File::new(vec![])).unwrap();
+        let mut nested = Dir::new();
+        nested.add_file(OsString::from("empty"), File::new(vec![])).unwrap();
+        sub.add_dir(OsString::from("nested"), nested).unwrap();
+        dir.add_dir(OsString::from("sub"), sub).unwrap();
+        dir.add_file(OsString::from("empty"), File::new(vec![])).unwrap();
+        dir.add_dir(OsString::from("already-empty"), Dir::new()).unwrap();
+
+        assert_eq!(dir.remove_files_where(|f| f.bytes().is_empty()), 3);
+        assert_eq!(dir.get(Path::new("empty")), None);
+        assert!(dir_at(&dir, "sub/nested").is_empty());
+        assert_eq!(dir.prune_empty(), 3);
+        let mut expected = load_data();
+        assert_eq!(dir, expected);
+
+        assert_eq!(expected.remove_files_where(|f| f.bytes().starts_with(b"file1")), 3);
+        assert_eq!(count_entries(&expected), 7);
+    }
+
+    #[test]
+    fn test_as_arc() {
+        let mut file = File::new(b"shared".to_vec());
+        let a = file.as_arc();
+        let b = file.clone_shared().as_arc();
+        assert!(Arc::ptr_eq(&a, &b));
+        assert!(!Arc::ptr_eq(&a, &file.clone().as_arc()));
+
+        file.bytes_mut().push(b'!');
+        assert_eq!(&a[..], b"shared");
+        assert_eq!(file.bytes(), b"shared!");
+        assert!(!Arc::ptr_eq(&a, &file.as_arc()));
+    }
+
+    #[test]
+    fn test_diff() {
+        let dir = load_data();
+        assert!(dir.diff(&dir.clone()).is_empty());
+
+        let mut other = dir.clone();
+        other.items.insert(OsString::from("file0-0"), Entry::file("changed"));
+        other.items.insert(OsString::from("dir0-1"), Entry::file("now a file"));
+        other.items.remove(OsStr::new("file0-2"));
+        other.add_dir(OsString::from("new"), load_data()).unwrap();
+        assert_eq!(dir.diff(&other), vec![
+            Diff::Removed(PathBuf::from("dir0-1")),
+            Diff::Added(PathBuf::from("dir0-1")),
+            Diff::ModifiedFile(PathBuf::from("file0-0")),
+            Diff::Removed(PathBuf::from("file0-2")),
+            Diff::Added(PathBuf::from("new")),
+        ]);
+    }
+
+    #[test]
+    fn test_diff_stream() {
+        let dir = load_data();
+        assert_eq!(dir.diff_stream(&dir).count(), 0);
+
+        let mut other = dir.clone();
+        other.modify_files(vec![
+            (PathBuf::from("dir0-0/dir1-0/file2-0"), b"changed".to_vec()),
+            (PathBuf::from("dir0-0/new"), vec![]),
+            (PathBuf::from("file0-1"), b"changed".to_vec()),
+        ]).unwrap();
+        other.items.insert(OsString::from("dir0-1"), Entry::file("now a file"));
+        other.items.remove(OsStr::new("file0-2"));
+        other.add_dir(OsString::from("added"), load_data()).unwrap();
+
+        let streamed: Vec<_> = dir.diff_stream(&other).collect();
+        assert_eq!(streamed, dir.diff(&other));
+        assert_eq!(streamed.len(), 7);
+        let reversed: Vec<_> = other.diff_stream(&dir).collect();
+        assert_eq!(reversed, other.diff(&dir));
+    }
+
+    #[test]
+    fn test_differs_from_disk() {
+        let dir = load_data();
+        assert_eq!(dir.differs_from_disk(&data_dir()).unwrap(), vec![]);
+
+        let out = out_dir("test_differs_from_disk");
+        dir.dump(&out).unwrap();
+        fs::write(out.join("file0-0"), b"file0-X\n").unwrap();
+        fs::write(out.join("dir0-0/file1-0"), b"different size").unwrap();
+        fs::remove_file(out.join("dir0-0/dir1-0/file2-0")).unwrap();
+        fs::remove_dir_all(out.join("dir0-1")).unwrap();
+        fs::write(out.join("dir0-1"), b"now a file").unwrap();
+        fs::create_dir(out.join("new")).unwrap();
+
+        let diffs = dir.differs_from_disk(&out).unwrap();
+        assert_eq!(diffs, dir.diff(&Dir::load(&out).unwrap()));
+        assert_eq!(diffs, vec![
+            Diff::Removed(PathBuf::from("dir0-0/dir1-0/file2-0")),
+            Diff::ModifiedFile(PathBuf::from("dir0-0/file1-0")),
+            Diff::Removed(PathBuf::from("dir0-1")),
+            Diff::Added(PathBuf::from("dir0-1")),
+            Diff::ModifiedFile(PathBuf::from("file0-0")),
+            Diff::Added(PathBuf::from("new")),
+        ]);
+        fs::remove_dir_all(&out).unwrap();
+    }
+
+    #[test]
+    fn test_diff_structure() {
+        let dir = load_data();
+        let mut other = dir.clone();
+        // contents changes are ignored
+        other.items.insert(OsString::from("file0-0"), Entry::file("changed"));
+        other.items.insert(OsString::from("dir0-1"), Entry::file("now a file"));
+        other.items.remove(OsStr::new("file0-2"));
+        other.add_dir(OsString::from("new"), Dir::new()).unwrap();
+
+        let (added, removed) = dir.diff_structure(&other);
+        assert_eq!(added, vec![PathBuf::from("dir0-1"), PathBuf::from("new")]);
+        assert_eq!(removed, vec![PathBuf::from("dir0-1"), PathBuf::from("file0-2")]);
+        assert_eq!(dir.diff_structure(&dir), (vec![], vec![]));
+    }
+
+    #[test]
+    fn test_intersection() {
+        let a = load_data();
+        let mut b = Dir::new();
+        b.load_into(Path::new("dir0-0/dir1-0"), &data_dir().join("dir0-0/dir1-0"),
+                    MergePolicy::Error).unwrap();
+        b.dir_mut_p(Path::new("dir0-0")).unwrap()
+            .add_file(OsString::from("file1-0"), File::new(b"different".to_vec())).unwrap();
+        b.add_dir(OsString::from("dir0-1"), Dir::new()).unwrap();
+        b.add_file(OsString::from("file0-1"), File::new(b"also different".to_vec())).unwrap();
+
+        let common = a.intersection(&b);
+        assert_eq!(count_entries(&common), 3);
+        assert_eq!(file_at(&common, "dir0-0/dir1-0/file2-0").bytes(), b"file2-0\n");
+        assert!(Arc::ptr_eq(&file_at(&common, "dir0-0/dir1-0/file2-0").as_arc(),
+                            &file_at(&a, "dir0-0/dir1-0/file2-0").as_arc()));
+        assert_eq!(common, b.intersection(&a));
+        assert_eq!(a.intersection(&a), a);
+    }
+
+    #[test]
+    fn test_diff_normalized() {
+        let mut a = Dir::new();
+        a.add_file(OsString::from("ws"), File::new(b"code  \nmore\t\n".to_vec())).unwrap();
+        a.add_file(OsString::from("real"), File::new(b"one\n".to_vec())).unwrap();
+        let mut b = Dir::new();
+        b.add_file(OsString::from("ws"), File::new(b"code\nmore\n".to_vec())).unwrap();
+        b.add_file(OsString::from("real"), File::new(b"two\n".to_vec())).unwrap();
+
+        let strip = |bytes: &[u8]| {
+            let text = String::from_utf8_lossy(bytes);
+            let lines: Vec<_> = text.lines().map(|l| l.trim_end()).collect();
+            lines.join("\n").into_bytes()
+        };
+        assert_eq!(a.diff_normalized(&b, strip), vec![Diff::ModifiedFile(PathBuf::from("real"))]);
+        assert_eq!(a.diff(&b).len(), 2);
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut dir = load_data();
+        assert_eq!(dir.stats(), TreeStats {
+            files: 7,
+            dirs: 3,
+            bytes: 56,
+            max_depth: 3,
+            largest_file: Some((PathBuf::from("dir0-0/dir1-0/file2-0"), 8)),
+            size_histogram: vec![0, 0, 0, 0, 7],
+        });
+
+        dir.add_file(OsString::from("empty"), File::new(vec![])).unwrap();
+        dir.add_file(OsString::from("big"), File::new(vec![0; 1024])).unwrap();
+        let stats = dir.stats();
+        assert_eq!(stats.largest_file, Some((PathBuf::from("big"), 1024)));
+        assert_eq!(stats.size_histogram, vec![1, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(Dir::new().stats(), TreeStats::default());
+    }
+
+    #[test]
+    fn test_new_files_versus() {
+        let dir = load_data();
+        let known: HashSet<u64> = ["file0-0\n", "file1-0\n", "elsewhere"].iter()
+            .map(|c| File::new(c.as_bytes().to_vec()).hash())
+            .collect();
+        let novel: Vec<_> = dir.new_files_versus(&known).into_iter().map(|(p, _)| p).collect();
+        assert_eq!(novel, vec![
+            PathBuf::from("dir0-0/dir1-0/file2-0"),
+            PathBuf::from("dir0-0/file1-1"),
+            PathBuf::from("file0-1"),
+            PathBuf::from("file0-2"),
+        ]);
+        assert_eq!(dir.new_files_versus(&HashSet::new()).len(), 7);
+    }
+
+    #[test]
+    fn test_shard() {
+        let mut dir = Dir::new();
+        for (i, size) in [500, 300, 250, 100, 90, 10].iter().enumerate() {
+            let mut sub = Dir::new();
+            sub.add_file(OsString::from("f"), File::new(vec![0; *size])).unwrap();
+            dir.add_dir(OsString::from(format!("d{}", i)), sub).unwrap();
+        }
+        dir.add_file(OsString::from("loose"), File::new(vec![0; 40])).unwrap();
+        let total = dir.total_size();
+
+        let shards = dir.clone().shard(2);
+        assert_eq!(shards.len(), 2);
+        let sizes: Vec<_> = shards.iter().map(Dir::total_size).collect();
+        assert_eq!(sizes.iter().sum::<u64>(), total);
+        let spread = sizes.iter().max().unwrap() - sizes.iter().min().unwrap();
+        assert!(spread <= 500, "sizes: {:?}", sizes);
+        assert_eq!(sizes, vec![650, 640]);
+
+        let mut joined = Dir::new();
+        for shard in shards {
+            joined.merge(shard, MergePolicy::Error).unwrap();
+        }
+        assert_eq!(joined, dir);
+        assert_eq!(Dir::new().shard(3), vec![Dir::new(), Dir::new(), Dir::new()]);
+    }
+
+    #[test]
+    fn test_largest_dirs() {
+        let mut dir = load_data();
+        assert_eq!(dir.total_size(), 7 * 8);
+        let mut heavy = Dir::new();
+        heavy.add_file(OsString::from("big"), File::new(vec![0; 1000])).unwrap();
+        let mut nested = Dir::new();
+        nested.add_file(OsString::from("medium"), File::new(vec![0; 100])).unwrap();
+        heavy.add_dir(OsString::from("nested"), nested).unwrap();
+        dir.add_dir(OsString::from("heavy"), heavy).unwrap();
+
+        assert_eq!(dir.total_size(), 7 * 8 + 1100);
+        assert_eq!(dir.largest_dirs(3), vec![
+            (PathBuf::from("heavy"), 1100),
+            (PathBuf::from("heavy/nested"), 100),
+            (PathBuf::from("dir0-0"), 24),
+        ]);
+        assert_eq!(dir.largest_dirs(100).len(), 5);
+    }
+
+    #[test]
+    fn test_prepend_insert_at() {
+        let mut file = File::new(b"body".to_vec());
+        file.prepend(b"// header\n");
+        assert_eq!(file.bytes(), b"// header\nbody");
+
+        let mut file = File::new(b"helloworld".to_vec());
+        file.insert_at(5, b", ").unwrap();
+        assert_eq!(file.bytes(), b"hello, world");
+        file.insert_at(12, b"!").unwrap();
+        assert_eq!(file.bytes(), b"hello, world!");
+
+        let err = file.insert_at(14, b"x").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(file.bytes(), b"hello, world!");
+    }
+
+    #[test]
+    fn test_tree_report() {
+        let dir = load_data();
+        let expected = "\
+. (56 bytes)
+├── dir0-0 (24 bytes)
+│   ├── dir1-0 (8 bytes)
+│   │   └── file2-0 (8 bytes)
+│   ├── file1-0 (8 bytes)
+│   └── file1-1 (8 bytes)
+├── dir0-1 (8 bytes)
+│   └── file1-0 (8 bytes)
+├── file0-0 (8 bytes)
+├── file0-1 (8 bytes)
+└── file0-2 (8 bytes)
+
+3 directories, 7 files, 56 bytes
+";
+        let opts = TreeReportOpts { file_sizes: true, dir_sizes: true, summary: true };
+        assert_eq!(dir.tree_report(opts).replace("\r\n", "\n"), expected);
+
+        let plain = dir.tree_report(TreeReportOpts::default());
+        assert_eq!(plain.lines().count(), 11);
+        assert!(plain.starts_with(".\n├── dir0-0\n"));
+    }
+
+    #[test]
+    fn test_load_files() {
+        let base = data_dir();
+        let files = vec![base.join("dir0-0/dir1-0/file2-0"), base.join("file0-1")];
+        let dir = Dir::load_files(&base, &files).unwrap();
+
+        assert_eq!(count_entries(&dir), 4);
+        assert_eq!(file_at(&dir, "dir0-0/dir1-0/file2-0").bytes(), b"file2-0\n");
+        assert_eq!(file_at(&dir, "file0-1").bytes(), b"file0-1\n");
+        assert_eq!(dir_at(&dir, "dir0-0").len(), 1);
+
+        let outside = vec![env::current_dir().unwrap().join("Cargo.toml")];
+        let err = Dir::load_files(&base, &outside).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_distinct_content_count() {
+        let mut dir = load_data();
+        // dir0-0/file1-0 and dir0-1/file1-0 hold the same data
+        assert_eq!(dir.distinct_content_count(), 6);
+        dir.add_file(OsString::from("copy"), File::new(b"file0-0\n".to_vec())).unwrap();
+        dir.add_file(OsString::from("unique"), File::new(b"unique".to_vec())).unwrap();
+        assert_eq!(dir.distinct_content_count(), 7);
+        assert_eq!(Dir::new().distinct_content_count(), 0);
+
+        assert_eq!(File::new(b"abc".to_vec()).hash(), 0xba78_16bf_8f01_cfea);
+    }
+
+    #[test]
+    fn test_modify_files() {
+        let mut dir = load_data();
+        dir.modify_files(vec![
+            (PathBuf::from("file0-0"), b"top".to_vec()),
+            (PathBuf::from("dir0-0/dir1-0/file2-0"), b"deep".to_vec()),
+            (PathBuf::from("dir0-0/dir1-0/new"), b"new".to_vec()),
+            (PathBuf::from("a/b/c"), b"created".to_vec()),
+            (PathBuf::from("a/b/c"), b"last wins".to_vec()),
+        ]).unwrap();
+        assert_eq!(file_at(&dir, "file0-0").bytes(), b"top");
+        assert_eq!(file_at(&dir, "dir0-0/dir1-0/file2-0").bytes(), b"deep");
+        assert_eq!(file_at(&dir, "dir0-0/dir1-0/new").bytes(), b"new");
+        assert_eq!(file_at(&dir, "a/b/c").bytes(), b"last wins");
+        assert_eq!(count_entries(&dir), 14);
+
+        let err = dir.modify_files(vec![(PathBuf::from("file0-1/x"), vec![])]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert!(err.to_string().contains("file0-1"), "{}", err);
+        let err = dir.modify_files(vec![(PathBuf::from("dir0-1"), vec![])]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+
+        let before = dir.clone();
+        for bad in &["x/..", "x/../y", "/abs", ""] {
+            let edits = vec![(PathBuf::from("ok"), vec![]), (PathBuf::from(bad), vec![])];
+            let err = dir.modify_files(edits).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+            assert_eq!(dir, before);
+        }
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut incoming = Dir::new();
+        incoming.add_file(OsString::from("file0-0"), File::new(b"new".to_vec())).unwrap();
+        let mut sub = Dir::new();
+        sub.add_file(OsString::from("added"), File::new(b"added".to_vec())).unwrap();
+        incoming.add_dir(OsString::from("dir0-0"), sub).unwrap();
+
+        let mut dir = load_data();
+        dir.merge(incoming.clone(), MergePolicy::Overwrite).unwrap();
+        assert_eq!(file_at(&dir, "file0-0").bytes(), b"new");
+        assert_eq!(file_at(&dir, "dir0-0/added").bytes(), b"added");
+        assert_eq!(file_at(&dir, "dir0-0/file1-0").bytes(), b"file1-0\n");
+
+        let mut dir = load_data();
+        dir.merge(incoming.clone(), MergePolicy::Skip).unwrap();
+        assert_eq!(file_at(&dir, "file0-0").bytes(), b"file0-0\n");
+        assert_eq!(file_at(&dir, "dir0-0/added").bytes(), b"added");
+
+        let mut dir = load_data();
+        let err = dir.merge(incoming, MergePolicy::Error).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(dir, load_data());
+    }
+
+    #[test]
+    fn test_merge_concat() {
+        let mut incoming = Dir::new();
+        incoming.modify_files(vec![
+            (PathBuf::from("file0-0"), b"more\n".to_vec()),
+            (PathBuf::from("dir0-0/added"), b"added".to_vec()),
+        ]).unwrap();
+
+        let mut dir = load_data();
+        dir.merge_concat(incoming, b"--\n").unwrap();
+        assert_eq!(file_at(&dir, "file0-0").bytes(), b"file0-0\n--\nmore\n");
+        assert_eq!(file_at(&dir, "dir0-0/added").bytes(), b"added");
+        assert_eq!(file_at(&dir, "dir0-0/file1-0").bytes(), b"file1-0\n");
+
+        let mut incoming = Dir::new();
+        incoming.modify_files(vec![
+            (PathBuf::from("file0-0"), b"more\n".to_vec()),
+            (PathBuf::from("dir0-0/dir1-0"), b"not a dir".to_vec()),
+        ]).unwrap();
+        let mut dir = load_data();
+        let err = dir.merge_concat(incoming, b"").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(dir, load_data());
+    }
+
+    #[test]
+    fn test_merge_preview() {
+        let mut incoming = Dir::new();
+        incoming.add_file(OsString::from("file0-0"), File::new(b"new".to_vec())).unwrap();
+        incoming.add_file(OsString::from("dir0-1"), File::new(b"replaces a dir".to_vec())).unwrap();
+        let mut sub = Dir::new();
+        sub.add_file(OsString::from("added"), File::new(vec![])).unwrap();
+        sub.add_file(OsString::from("file1-1"), File::new(vec![])).unwrap();
+        incoming.add_dir(OsString::from("dir0-0"), sub).unwrap();
+        incoming.add_dir(OsString::from("new"), load_data()).unwrap();
+
+        let dir = load_data();
+        let plan = dir.merge_preview(&incoming, MergePolicy::Overwrite);
+        assert_eq!(plan, MergePlan {
+            added: vec![PathBuf::from("dir0-0/added"), PathBuf::from("new")],
+            overwritten: vec![PathBuf::from("dir0-0/file1-1"), PathBuf::from("dir0-1"),
+                              PathBuf::from("file0-0")],
+            skipped: vec![],
+            conflicts: vec![],
+        });
+
+        let mut merged = dir.clone();
+        merged.merge(incoming.clone(), MergePolicy::Overwrite).unwrap();
+        // dir0-1 changed from dir to file, so also shows up as added
+        let (mut added, _) = dir.diff_structure(&merged);
+        added.retain(|p| !plan.overwritten.contains(p));
+        assert_eq!(added, plan.added);
+        for path in &plan.overwritten {
+            assert_eq!(merged.get(path), incoming.get(path));
+        }
+
+        let plan = dir.merge_preview(&incoming, MergePolicy::Skip);
+        assert_eq!(plan.skipped.len(), 3);
+        let mut merged = dir.clone();
+        merged.merge(incoming.clone(), MergePolicy::Skip).unwrap();
+        for path in &plan.skipped {
+            assert_eq!(merged.get(path), dir.get(path));
+        }
+
+        let plan = dir.merge_preview(&incoming, MergePolicy::Error);
+        assert_eq!(plan.conflicts.len(), 3);
+        assert!(dir.clone().merge(incoming, MergePolicy::Error).is_err());
+    }
+
+    #[test]
+    fn test_load_into() {
+        let data = data_dir();
+        let mut dir = Dir::new();
+        dir.load_into(Path::new("mnt/a"), &data.join("dir0-0"), MergePolicy::Error).unwrap();
+        dir.load_into(Path::new("mnt/b"), &data.join("dir0-1"), MergePolicy::Error).unwrap();
+        dir.load_into(Path::new("mnt/b"), &data.join("dir0-0/dir1-0"), MergePolicy::Error).unwrap();
+
+        assert_eq!(*dir_at(&dir, "mnt/a"), *dir_at(&load_data(), "dir0-0"));
+        assert_eq!(file_at(&dir, "mnt/b/file1-0").bytes(), b"file1-0\n");
+        assert_eq!(file_at(&dir, "mnt/b/file2-0").bytes(), b"file2-0\n");
+        assert_eq!(dir_at(&dir, "mnt").len(), 2);
+
+        let err = dir.load_into(Path::new("mnt/a"), &data.join("dir0-1"), MergePolicy::Error);
+        assert_eq!(err.unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+    }
+
+    #[test]
+    fn test_fsck() {
+        let mut dir = load_data();
+        assert!(dir.fsck().is_empty());
+
+        let mut sub = Dir::new();
+        sub.add_file(OsString::from("a/b"), File::new(vec![])).unwrap();
+        sub.add_file(OsString::from("c\\d"), File::new(vec![])).unwrap();
+        sub.add_file(OsString::from("Readme"), File::new(vec![])).unwrap();
+        sub.add_file(OsString::from("README"), File::new(vec![])).unwrap();
+        dir.add_dir(OsString::from("sub"), sub).unwrap();
+        dir.add_file(OsString::from(""), File::new(vec![])).unwrap();
+
+        let mut expected = vec![
+            Issue { path: PathBuf::from(""), reason: IssueReason::EmptyName },
+            Issue { path: PathBuf::from("sub/README"), reason: IssueReason::CaseCollision },
+            Issue { path: PathBuf::from("sub/Readme"), reason: IssueReason::CaseCollision },
+            Issue { path: PathBuf::from("sub/a/b"), reason: IssueReason::Separator },
+        ];
+        // a backslash is only a separator on windows
+        if cfg!(windows) {
+            expected.push(Issue { path: PathBuf::from("sub/c\\d"), reason: IssueReason::Separator });
+        }
+        assert_eq!(dir.fsck(), expected);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStringExt;
+            let mut raw = Dir::new();
+            raw.add_file(OsString::from_vec(b"\xff".to_vec()), File::new(vec![])).unwrap();
+            raw.add_file(OsString::from_vec(b"\xfe".to_vec()), File::new(vec![])).unwrap();
+            raw.add_file(OsString::from_vec(b"A\xff".to_vec()), File::new(vec![])).unwrap();
+            raw.add_file(OsString::from_vec(b"a\xff".to_vec()), File::new(vec![])).unwrap();
+            let paths: Vec<_> = raw.fsck().into_iter()
+                .map(|i| { assert_eq!(i.reason, IssueReason::CaseCollision); i.path })
+                .collect();
+            assert_eq!(paths, vec![PathBuf::from(OsString::from_vec(b"A\xff".to_vec())),
+                                   PathBuf::from(OsString::from_vec(b"a\xff".to_vec()))]);
+        }
+    }
+
+    #[test]
+    fn test_split_top_level() {
+        let dir = load_data();
+        let (dirs, files) = dir.clone().split_top_level();
+        assert_eq!(dirs.len(), 2);
+        assert_eq!(dirs[OsStr::new("dir0-1")], *dir_at(&dir, "dir0-1"));
+        assert_eq!(files.len(), 3);
+
+        let mut joined = Dir::new();
+        for (name, d) in dirs {
+            joined.add_dir(name, d).unwrap();
+        }
+        for (name, f) in files {
+            joined.add_file(name, f).unwrap();
+        }
+        assert_eq!(joined, dir);
+    }
+
+    #[test]
+    fn test_detect_format() {
+        let detect = |bytes: &[u8]| File::new(bytes.to_vec()).detect_format();
+        assert_eq!(detect(b"\x89PNG\r\n\x1a\n\x00\x00"), Some(FileFormat::Png));
+        assert_eq!(detect(b"\xff\xd8\xff\xe0"), Some(FileFormat::Jpeg));
+        assert_eq!(detect(b"GIF89a..."), Some(FileFormat::Gif));
+        assert_eq!(detect(b"RIFF\x10\x00\x00\x00WEBPVP8 "), Some(FileFormat::Webp));
+        assert_eq!(detect(b"RIFF\x10\x00\x00\x00WAVEfmt "), None);
+        assert_eq!(detect(b"%PDF-1.7"), Some(FileFormat::Pdf));
+        assert_eq!(detect(b"PK\x03\x04\x14\x00"), Some(FileFormat::Zip));
+        assert_eq!(detect(b"\x1f\x8b\x08\x00"), Some(FileFormat::Gzip));
+        assert_eq!(detect(b"\x7fELF\x02\x01"), Some(FileFormat::Elf));
+        assert_eq!(detect(b"\x00asm\x01\x00\x00\x00"), Some(FileFormat::Wasm));
+
+        let mut tar = vec![0; 512];
+        tar[257..263].copy_from_slice(b"ustar\x00");
+        assert_eq!(detect(&tar), Some(FileFormat::Tar));
+
+        assert_eq!(detect(b"plain text"), None);
+        assert_eq!(detect(b""), None);
+        assert_eq!(detect(b"\x89PN"), None);
+    }
+
+    #[test]
+    fn test_dump_checked() {
+        let dir = load_data();
+        let tmp = out_dir("test_out_checked");
+
+        let limits = DumpLimits { max_depth: Some(2), ..DumpLimits::default() };
+        match dir.dump_checked(&tmp, limits) {
+            Err(DumpLimitError::TooDeep(p)) => assert_eq!(p, PathBuf::from("dir0-0/dir1-0/file2-0")),
+            r => panic!("unexpected: {:?}", r),
+        }
+        assert!(!tmp.exists());
+
+        let mut long = tmp.as_os_str().len() + "/dir0-0/dir1-0".len();
+        let limits = DumpLimits { max_path_len: Some(long), ..DumpLimits::default() };
+        match dir.dump_checked(&tmp, limits) {
+            Err(DumpLimitError::PathTooLong(p)) => assert_eq!(p, PathBuf::from("dir0-0/dir1-0/file2-0")),
+            r => panic!("unexpected: {:?}", r),
+        }
+        assert!(!tmp.exists());
+
+        let mut windows = Dir::new();
+        windows.add_file(OsString::from("aux.txt"), File::new(vec![])).unwrap();
+        let limits = DumpLimits { windows_names: true, ..DumpLimits::default() };
+        match windows.dump_checked(&tmp, limits) {
+            Err(DumpLimitError::IllegalName(p)) => assert_eq!(p, PathBuf::from("aux.txt")),
+            r => panic!("unexpected: {:?}", r),
+        }
+        windows.dump_checked(&tmp, DumpLimits::default()).unwrap();
+        fs::remove_dir_all(&tmp).unwrap();
+
+        // legal on linux, but not on windows
+        let mut linux = Dir::new();
+        linux.add_file(OsString::from("Makefile"), File::new(vec![])).unwrap();
+        linux.add_file(OsString::from("makefile"), File::new(vec![])).unwrap();
+        match linux.dump_checked(&tmp, limits) {
+            Err(DumpLimitError::IllegalName(p)) => assert_eq!(p, PathBuf::from("Makefile")),
+            r => panic!("unexpected: {:?}", r),
+        }
+        assert!(!tmp.exists());
+        if cfg!(target_os = "linux") {
+            linux.add_file(OsString::from("a\\b"), File::new(vec![])).unwrap();
+            linux.dump_checked(&tmp, DumpLimits::default()).unwrap();
+            assert_eq!(Dir::load(&tmp).unwrap(), linux);
+            fs::remove_dir_all(&tmp).unwrap();
+
+            let mut backslash = Dir::new();
+            backslash.add_file(OsString::from("a\\b"), File::new(vec![])).unwrap();
+            match backslash.dump_checked(&tmp, limits) {
+                Err(DumpLimitError::IllegalName(p)) => assert_eq!(p, PathBuf::from("a\\b")),
+                r => panic!("unexpected: {:?}", r),
+            }
+        }
+
+        long += "/file2-0".len();
+        let limits = DumpLimits {
+            max_path_len: Some(long),
+            max_depth: Some(3),
+            windows_names: true,
+        };
+        dir.dump_checked(&tmp, limits).unwrap();
+        assert_eq!(Dir::load(&tmp).unwrap(), dir);
+        fs::remove_dir_all(tmp).unwrap();
+    }
+
+    #[test]
+    fn test_dump_buffered() {
+        let mut dir = load_data();
+        dir.add_file(OsString::from("large"), File::new(random_bytes(100_000))).unwrap();
+        let plain = out_dir("test_out_plain");
+        let buffered = out_dir("test_out_buffered");
+        dir.dump(&plain).unwrap();
+        dir.dump_buffered(&buffered, 4096).unwrap();
+
+        let loaded = Dir::load(&buffered).unwrap();
+        assert_eq!(loaded, Dir::load(&plain).unwrap());
+        assert_eq!(loaded, dir);
+        fs::remove_dir_all(plain).unwrap();
+        fs::remove_dir_all(buffered).unwrap();
+    }
+
+    #[test]
+    fn test_paths() {
+        let dir = load_data();
+        let paths: Vec<_> = dir.paths().collect();
+        assert_eq!(paths.len(), count_entries(&dir));
+        for path in &paths {
+            assert!(dir.get(path).is_some(), "{}", path.display());
+        }
+        let nested = paths.iter().position(|p| p == Path::new("dir0-0/dir1-0/file2-0")).unwrap();
+        let parent = paths.iter().position(|p| p == Path::new("dir0-0/dir1-0")).unwrap();
+        assert!(parent < nested);
+        assert_eq!(Dir::new().paths().count(), 0);
+    }
+
+    #[test]
+    fn test_transaction_rollback() {
+        let mut dir = load_data();
+        let expected = dir.clone();
+        let shared = file_at(&dir, "file0-0").as_arc();
+        {
+            let mut t = dir.begin();
+            t.add_file(OsString::from("new"), File::new(b"new".to_vec())).unwrap();
+            t.add_dir(OsString::from("new-dir"), Dir::new()).unwrap();
+            assert!(t.add_file(OsString::from("file0-0"), File::new(vec![])).is_err());
+            t.rollback();
+        }
+        assert_eq!(dir, expected);
+        // the snapshot shared the file buffers instead of copying them
+        assert!(Arc::ptr_eq(&shared, &file_at(&dir, "file0-0").as_arc()));
+
+        {
+            let mut t = dir.begin();
+            t.add_file(OsString::from("dropped"), File::new(vec![])).unwrap();
+        }
+        assert_eq!(dir, expected);
+    }
+
+    #[test]
+    fn test_transaction_commit() {
+        let mut dir = load_data();
+        let expected = dir.clone();
+        {
+            let mut t = dir.begin();
+            t.add_file(OsString::from("new"), File::new(b"new".to_vec())).unwrap();
+            t.commit();
+        }
+        assert_ne!(dir, expected);
+        assert_eq!(count_entries(&dir), count_entries(&expected) + 1);
+    }
+
+    #[test]
+    fn test_entries_where() {
+        let dir = load_data();
+        let mut dirs: Vec<_> = dir.entries_where(|_, e| matches!(*e, Entry::Dir(_)))
+            .map(|(n, _)| n.clone())
+            .collect();
+        dirs.sort();
+        assert_eq!(dirs, vec![OsString::from("dir0-0"), OsString::from("dir0-1")]);
+        assert_eq!(dir.entries_where(|n, _| n == "file0-1").count(), 1);
+    }
+
+    #[test]
+    fn test_entry_constructors() {
+        assert_eq!(Entry::file("hello"), Entry::File(File::new(b"hello".to_vec())));
+        assert_eq!(Entry::file(vec![1, 2, 3]), Entry::File(File::new(vec![1, 2, 3])));
+        assert_eq!(Entry::file(&b"raw"[..]), Entry::File(File::new(b"raw".to_vec())));
+        assert_eq!(Entry::empty_dir(), Entry::Dir(Dir::new()));
     }
 }